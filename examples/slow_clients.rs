@@ -0,0 +1,121 @@
+//! Measures how the server copes with slow clients.
+//!
+//! Opens a number of connections that trickle their request in a byte at a time, and while they're open repeatedly
+//! times a normal request on a fresh connection. If given the server's PID, also reports how much CPU time the server
+//! burned over the run (Linux only, read from `/proc`).
+//!
+//! # Usage
+//! `cargo run --release --example slow_clients -- <address> <slow connections> <seconds> [server pid]`
+
+use std::{
+    env,
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+const SLOW_PREFIX: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: ";
+
+/// Total user + system CPU time of the given process, in seconds.
+fn cpu_seconds(pid: u32) -> Option<f64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name may contain spaces, so skip past its closing parenthesis first.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split(' ').collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    // USER_HZ is 100 on every Linux we care about.
+    Some((utime + stime) / 100.0)
+}
+
+fn timed_request(address: &str) -> std::io::Result<Duration> {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(REQUEST)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(start.elapsed())
+}
+
+fn slow_client(address: String, until: Instant) {
+    // The server is free to drop us for idling, in which case we just come back.
+    while Instant::now() < until {
+        let Ok(mut stream) = TcpStream::connect(&address) else {
+            eprintln!("Slow client couldn't connect.");
+            return;
+        };
+
+        // Never finish the request, just keep the server waiting on one never-ending header.
+        for byte in SLOW_PREFIX.iter().chain(std::iter::repeat(&b'a')) {
+            if Instant::now() >= until || stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        eprintln!("Usage: {} <address> <slow connections> <seconds> [server pid]", args[0]);
+        std::process::exit(1);
+    }
+
+    let address = args[1].clone();
+    let slow: usize = args[2].parse().expect("slow connections must be a number");
+    let seconds: u64 = args[3].parse().expect("seconds must be a number");
+    let pid: Option<u32> = args.get(4).map(|v| v.parse().expect("pid must be a number"));
+
+    let until = Instant::now() + Duration::from_secs(seconds);
+    let slow_clients: Vec<_> = (0..slow)
+        .map(|_| {
+            let address = address.clone();
+            thread::spawn(move || slow_client(address, until))
+        })
+        .collect();
+
+    // Give the slow clients a moment to connect before measuring.
+    thread::sleep(Duration::from_millis(500));
+    let cpu_start = pid.and_then(cpu_seconds);
+    let wall_start = Instant::now();
+
+    let mut latencies = Vec::new();
+    while Instant::now() < until {
+        match timed_request(&address) {
+            Ok(v) => latencies.push(v),
+            Err(e) => eprintln!("Request failed: {e}"),
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let wall = wall_start.elapsed().as_secs_f64();
+    let cpu_end = pid.and_then(cpu_seconds);
+
+    for c in slow_clients {
+        let _ = c.join();
+    }
+
+    latencies.sort();
+    if latencies.is_empty() {
+        println!("No requests completed.");
+        return;
+    }
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{} requests alongside {slow} slow clients: p50 {:?}, p99 {:?}, max {:?}",
+        latencies.len(),
+        percentile(0.5),
+        percentile(0.99),
+        latencies[latencies.len() - 1]
+    );
+
+    if let (Some(start), Some(end)) = (cpu_start, cpu_end) {
+        println!(
+            "Server CPU: {:.2}s over {wall:.1}s ({:.0}% of one core)",
+            end - start,
+            (end - start) / wall * 100.0
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! Publishes a tick a second to a Server-Sent Events channel.
+//!
+//! Try it with `curl -N http://127.0.0.1:8090/clock`, and pick up from an earlier tick with
+//! `curl -N -H "Last-Event-ID: 3" http://127.0.0.1:8090/clock`.
+//!
+//! # Usage
+//! `cargo run --example sse`
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    app::{ScheduleRunnerPlugin, ScheduleRunnerSettings},
+    log::LogPlugin,
+    prelude::*,
+};
+use bevyblog::{
+    config::ServiceConfig,
+    http::HttpRequestPlugin,
+    page::{
+        sse::{HttpSseBundle, HttpSseChannel},
+        HttpPageHandlerPlugin,
+    },
+};
+
+const CONFIG: &str = r#"(sitemaps: [], bind_addresses: ["127.0.0.1:8090"])"#;
+
+#[derive(Component)]
+struct Clock {
+    ticks: u64,
+    last: Instant,
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn((
+        HttpSseBundle::new(PathBuf::from("/clock"), 16, Duration::from_secs(15)),
+        Clock {
+            ticks: 0,
+            last: Instant::now(),
+        },
+    ));
+}
+
+fn tick(mut clocks: Query<(&mut HttpSseChannel, &mut Clock)>) {
+    for (mut channel, mut clock) in clocks.iter_mut() {
+        if clock.last.elapsed() < Duration::from_secs(1) {
+            continue;
+        }
+
+        clock.last += Duration::from_secs(1);
+        clock.ticks += 1;
+        channel.publish(Some("tick"), format!("{}", clock.ticks));
+    }
+}
+
+fn main() {
+    let config: ServiceConfig = ron::from_str(CONFIG).unwrap();
+
+    App::new()
+        .add_plugin(CorePlugin::default())
+        .add_plugin(LogPlugin::default())
+        .add_plugin(AssetPlugin::default())
+        .insert_resource(config)
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_micros(8333)))
+        .add_plugin(ScheduleRunnerPlugin)
+        .add_plugin(HttpRequestPlugin::default())
+        .add_plugin(HttpPageHandlerPlugin::default())
+        .add_startup_system(setup)
+        .add_system(tick)
+        .run();
+}
@@ -0,0 +1,79 @@
+//! Streams a response out a line at a time over many frames.
+//!
+//! Every request gets a `200 OK` head straight away, followed by a line of "progress" per frame until it's done.
+//! Try it with `curl -N http://127.0.0.1:8090/`.
+//!
+//! # Usage
+//! `cargo run --example streaming`
+
+use std::time::Duration;
+
+use bevy::{
+    app::{ScheduleRunnerPlugin, ScheduleRunnerSettings},
+    log::LogPlugin,
+    prelude::*,
+};
+use bevyblog::{
+    config::ServiceConfig,
+    http::{
+        events::{HttpRequestReceivedEvent, HttpRequestReplyEvent},
+        stream::HttpResponseStream,
+        HttpRequestPlugin,
+    },
+};
+use http::{header::CONTENT_TYPE, Response};
+
+const CONFIG: &str = r#"(sitemaps: [], bind_addresses: ["127.0.0.1:8090"])"#;
+const LINES: u32 = 300;
+/// Don't queue more than this much for a slow client, wait for it to catch up instead.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+#[derive(Component)]
+struct Progress {
+    line: u32,
+}
+
+fn start_streams(
+    mut requests: EventReader<HttpRequestReceivedEvent>,
+    mut replies: EventWriter<HttpRequestReplyEvent>,
+    mut commands: Commands,
+) {
+    for request in requests.iter() {
+        let head = Response::builder()
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(())
+            .unwrap();
+        replies.send(HttpRequestReplyEvent::streaming(head, request.ent));
+        commands.entity(request.ent).insert(Progress { line: 0 });
+    }
+}
+
+fn write_progress(mut streams: Query<(&mut HttpResponseStream, &mut Progress)>) {
+    for (mut stream, mut progress) in streams.iter_mut() {
+        if stream.buffered() > MAX_BUFFERED || progress.line >= LINES {
+            continue;
+        }
+
+        progress.line += 1;
+        stream.send(format!("line {} of {LINES}\n", progress.line));
+        if progress.line == LINES {
+            stream.finish();
+        }
+    }
+}
+
+fn main() {
+    let config: ServiceConfig = ron::from_str(CONFIG).unwrap();
+
+    App::new()
+        .add_plugin(CorePlugin::default())
+        .add_plugin(LogPlugin::default())
+        .add_plugin(AssetPlugin::default())
+        .insert_resource(config)
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_micros(8333)))
+        .add_plugin(ScheduleRunnerPlugin)
+        .add_plugin(HttpRequestPlugin::default())
+        .add_system(start_streams)
+        .add_system(write_progress)
+        .run();
+}
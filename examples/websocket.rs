@@ -0,0 +1,102 @@
+//! A bare bones live feed over WebSockets.
+//!
+//! Opening the page connects a WebSocket back to the server, and every message sent on one is broadcast to all of
+//! them. Open http://127.0.0.1:8090/ in a couple of tabs to try it.
+//!
+//! # Usage
+//! `cargo run --example websocket`
+
+use std::time::Duration;
+
+use bevy::{
+    app::{ScheduleRunnerPlugin, ScheduleRunnerSettings},
+    log::LogPlugin,
+    prelude::*,
+};
+use bevyblog::{
+    config::ServiceConfig,
+    http::{
+        events::{HttpRequestReceivedEvent, HttpRequestReplyEvent},
+        websocket::{
+            is_websocket_upgrade, HttpWebSocket, HttpWebSocketClosedEvent, HttpWebSocketMessageEvent,
+            WebSocketMessage,
+        },
+        HttpRequestPlugin,
+    },
+};
+use http::{header::CONTENT_TYPE, Response};
+use hyper::Body;
+
+const CONFIG: &str = r#"(sitemaps: [], bind_addresses: ["127.0.0.1:8090"])"#;
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<body>
+<form id="form"><input id="text" autocomplete="off"><button>Send</button></form>
+<ul id="feed"></ul>
+<script>
+const socket = new WebSocket(`ws://${location.host}/`);
+socket.onmessage = (e) => {
+    const item = document.createElement("li");
+    item.textContent = e.data;
+    document.getElementById("feed").prepend(item);
+};
+document.getElementById("form").onsubmit = (e) => {
+    e.preventDefault();
+    socket.send(document.getElementById("text").value);
+    document.getElementById("text").value = "";
+};
+</script>
+</body>
+</html>
+"#;
+
+fn accept(mut requests: EventReader<HttpRequestReceivedEvent>, mut replies: EventWriter<HttpRequestReplyEvent>) {
+    for request in requests.iter() {
+        if is_websocket_upgrade(&request.body) {
+            replies.send(HttpRequestReplyEvent::websocket(&request.body, request.ent).unwrap());
+            continue;
+        }
+
+        let page = Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(PAGE))
+            .unwrap();
+        replies.send(HttpRequestReplyEvent::new(Ok(page), request.ent));
+    }
+}
+
+fn broadcast(mut messages: EventReader<HttpWebSocketMessageEvent>, mut sockets: Query<&mut HttpWebSocket>) {
+    for ev in messages.iter() {
+        let WebSocketMessage::Text(text) = &ev.message else {
+            continue;
+        };
+
+        for mut socket in sockets.iter_mut() {
+            // A client that's fallen this far behind can miss a message or two.
+            let _ = socket.send(format!("{:?}: {text}", ev.ent));
+        }
+    }
+}
+
+fn goodbye(mut closed: EventReader<HttpWebSocketClosedEvent>) {
+    for ev in closed.iter() {
+        info!("{:?} left.", ev.ent);
+    }
+}
+
+fn main() {
+    let config: ServiceConfig = ron::from_str(CONFIG).unwrap();
+
+    App::new()
+        .add_plugin(CorePlugin::default())
+        .add_plugin(LogPlugin::default())
+        .add_plugin(AssetPlugin::default())
+        .insert_resource(config)
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_micros(8333)))
+        .add_plugin(ScheduleRunnerPlugin)
+        .add_plugin(HttpRequestPlugin::default())
+        .add_system(accept)
+        .add_system(broadcast)
+        .add_system(goodbye)
+        .run();
+}
@@ -1,14 +1,450 @@
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf, str::FromStr};
 
 use bevy::prelude::Resource;
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
 
 #[derive(Debug, Deserialize, Resource, Clone)]
 pub struct ServiceConfig {
     /// The site map assets to load for this site.
     pub sitemaps: Vec<PathBuf>,
     /// The bind addresses to utilize, in order of preference.
+    /// Unix domain sockets are given as `unix:` followed by the path of the socket file.
     /// # Example
-    /// `["0.0.0.0:8080","0.0.0.0:8081"]`
+    /// `["0.0.0.0:8080","0.0.0.0:8081","unix:/run/bevyblog.sock"]`
     pub bind_addresses: Vec<String>,
-}
\ No newline at end of file
+    /// The permissions given to unix domain socket files, i.e. `0o660`. They're bound in a private directory next to the
+    /// path and set before being moved into place. Left as the umask makes them if unset.
+    #[serde(default)]
+    pub unix_socket_mode: Option<u32>,
+    /// How the bind addresses are used, see [`BindMode`]. Defaults to [`BindMode::FirstAvailable`].
+    #[serde(default)]
+    pub bind_mode: BindMode,
+    /// Persistent connection settings.
+    #[serde(default)]
+    pub keep_alive: KeepAliveConfig,
+    /// HTTP/2 settings.
+    #[serde(default)]
+    pub http2: Http2Config,
+    /// Limits on what clients may send us, and how long they're kept waiting.
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Per client rate limiting. Off by default.
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Graceful shutdown settings.
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    /// Settings for finding the real client address when we're behind a reverse proxy.
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    /// Headers added to or removed from every response.
+    #[serde(default)]
+    pub headers: HeadersConfig,
+    /// Compression of static assets for clients that accept it.
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Prometheus metrics settings. Leave this out to not serve metrics.
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    /// Access log settings. Leave this out to not keep an access log.
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+    /// TLS settings. Leave this out to only serve plain HTTP.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+/// Controls how [`ServiceConfig::bind_addresses`] is turned into listeners.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BindMode {
+    /// Try each address in order, and listen only on the first one that binds.
+    #[default]
+    FirstAvailable,
+    /// Listen on every address at once. Failing to bind any of them is fatal.
+    All,
+}
+
+/// Settings for HTTP/1.1 persistent connections.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct KeepAliveConfig {
+    /// How long, in seconds, a connection with no outstanding requests is kept open.
+    pub idle_timeout_secs: u64,
+    /// How many requests a single connection may carry before it's closed. `1` disables keep-alive.
+    pub max_requests: usize,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 5,
+            max_requests: 100,
+        }
+    }
+}
+
+/// Settings for HTTP/2, which is spoken either with prior knowledge (h2c) or when negotiated through ALPN over TLS.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Http2Config {
+    /// Whether to accept HTTP/2 at all.
+    pub enabled: bool,
+    /// How many streams (and thus requests) a client may have open on one connection at once.
+    pub max_concurrent_streams: u32,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_concurrent_streams: 100,
+        }
+    }
+}
+
+/// Limits on what clients may send us, and how long they're kept waiting.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// The largest request body, in bytes, that's accepted. Anything larger gets a 413 (Payload Too Large).
+    pub max_request_body_size: usize,
+    /// The largest WebSocket message, in bytes, that's accepted, once put back together from its fragments. Clients
+    /// sending anything larger are disconnected with a 1009 (Message Too Big) close. Each open socket may buffer up to
+    /// this much.
+    pub max_websocket_message_size: usize,
+    /// How long, in seconds, a request may go without a reply before it's answered with a 504 (Gateway Timeout).
+    /// Site maps can override this per route.
+    pub request_timeout_secs: u64,
+    /// How many connections may be open at once. New ones past this get a 503 (Service Unavailable) and are closed.
+    pub max_connections: usize,
+    /// How many requests may be waiting on a reply at once. New ones past this get a 503 (Service Unavailable).
+    pub max_queued_requests: usize,
+    /// The `Retry-After`, in seconds, sent alongside those 503s.
+    pub retry_after_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_request_body_size: 1024 * 1024,
+            max_websocket_message_size: 1024 * 1024,
+            request_timeout_secs: 30,
+            max_connections: 1024,
+            max_queued_requests: 1024,
+            retry_after_secs: 5,
+        }
+    }
+}
+
+/// Settings for limiting how fast each client (by IP address) may make requests, using a token bucket.
+/// Clients over the limit get a 429 (Too Many Requests). Site maps can set their own limits for specific routes.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Whether to rate limit at all. Routes with their own limits in a site map are limited either way.
+    pub enabled: bool,
+    /// How many requests a second a client may keep up. Has to be more than zero.
+    #[serde(deserialize_with = "positive_rate")]
+    pub requests_per_second: f64,
+    /// The size of each client's bucket, which is the most requests they may make back to back before they're held
+    /// to the sustained rate. Has to be at least one.
+    #[serde(deserialize_with = "positive_burst")]
+    pub burst: u32,
+    /// Whether each route (handler path pattern) gets its own bucket per client, rather than one for the whole site.
+    pub per_route: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            requests_per_second: 10.0,
+            burst: 20,
+            per_route: false,
+        }
+    }
+}
+
+/// Settings for shutting down on SIGTERM, SIGINT, or an in-app request.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// How long, in seconds, open connections are given to finish their requests before they're cut off.
+    pub grace_period_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { grace_period_secs: 10 }
+    }
+}
+
+/// Settings for finding the real client address when we're behind a reverse proxy.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Which bind addresses expect every connection to start with a HAProxy PROXY protocol (v1 or v2) header,
+    /// written exactly as they appear in [`ServiceConfig::bind_addresses`].
+    pub proxy_protocol_listeners: Vec<String>,
+    /// The proxies whose forwarding headers are believed, as CIDRs such as `10.0.0.0/8` or single addresses.
+    /// `unix` trusts everything connecting over a unix domain socket.
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// Which header the trusted proxies append the client address to, see [`ForwardedHeader`].
+    pub forwarded_header: ForwardedHeader,
+}
+
+/// The forwarding header the trusted proxies write. Only this one is read; the other is left as the client sent it,
+/// as a proxy that only appends to one passes the other through untouched for anyone to fill in.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, as nginx, HAProxy and most load balancers write.
+    #[default]
+    XForwardedFor,
+    /// The standard `Forwarded` header from RFC 7239.
+    Forwarded,
+}
+
+/// Headers applied to every response, after any a site map sets for the route. The only exception is the canned 503
+/// written to connections turned away for being over [`LimitsConfig::max_connections`].
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HeadersConfig {
+    /// Headers added to responses that don't already have them, like `("Server", "bevyblog")` or security headers.
+    pub add: Vec<(String, String)>,
+    /// Headers taken out of every response, whatever set them. These go before [`Self::add`] is applied, so a header
+    /// in both is always replaced with ours.
+    pub remove: Vec<String>,
+}
+
+/// Settings for compressing static assets. Each asset is compressed once when it's loaded (and again whenever it's
+/// reloaded), and the best encoding the client accepts is picked for each request.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// The encodings to offer, most preferred first. Used to break ties between encodings the client likes equally.
+    pub encodings: Vec<ContentEncoding>,
+    /// Assets smaller than this many bytes aren't worth compressing, and are always sent as they are.
+    pub min_size: usize,
+    /// The MIME types worth compressing, like `text/html`. A type ending in `/*` covers everything under it.
+    pub mime_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            encodings: vec![ContentEncoding::Brotli, ContentEncoding::Zstd, ContentEncoding::Gzip],
+            min_size: 1024,
+            mime_types: vec![
+                "text/*".to_string(),
+                "application/javascript".to_string(),
+                "application/json".to_string(),
+                "application/xml".to_string(),
+                "image/svg+xml".to_string(),
+                "image/x-icon".to_string(),
+            ],
+        }
+    }
+}
+
+/// A `Content-Encoding` we can compress responses with.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentEncoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn token(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Settings for serving metrics in the Prometheus text format.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// The path the metrics are served on.
+    pub path: String,
+    /// Addresses to listen on for the metrics alone, in the same form as [`ServiceConfig::bind_addresses`]. These are
+    /// always bound on top of the bind addresses, and serve nothing but the metrics. When set, the metrics aren't
+    /// served anywhere else, so keep these off the public network. Leave empty to serve the metrics on every listener.
+    pub listeners: Vec<String>,
+    /// The upper bounds of the request latency histogram buckets, in seconds. They're sorted, and duplicates dropped,
+    /// as they're loaded.
+    #[serde(deserialize_with = "histogram_buckets")]
+    pub latency_buckets: Vec<f64>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            path: "/metrics".to_string(),
+            listeners: Vec::new(),
+            latency_buckets: vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0],
+        }
+    }
+}
+
+/// A proxy (or range of them) trusted to report the client's address, see [`ProxyConfig::trusted_proxies`].
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum TrustedProxy {
+    Cidr(IpCidr),
+    Unix,
+}
+
+impl TryFrom<String> for TrustedProxy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value == "unix" {
+            return Ok(TrustedProxy::Unix);
+        }
+
+        value.parse().map(TrustedProxy::Cidr)
+    }
+}
+
+/// A range of IP addresses, like `192.168.0.0/16` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|e| format!("bad address in \"{s}\": {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("bad prefix length in \"{s}\""))?,
+            None => max,
+        };
+
+        Ok(IpCidr { addr, prefix })
+    }
+}
+
+/// Settings for the access log, which gets a line for every finished request.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    /// The file to write to. Rotated files get the time they were rotated appended to their name.
+    pub path: PathBuf,
+    /// How each request is written out. Defaults to [`AccessLogFormat::Combined`].
+    pub format: AccessLogFormat,
+    /// Rotate the file before it grows past this many bytes. `0` disables size based rotation.
+    pub max_file_size: u64,
+    /// Rotate the file at the first request of each day (local time).
+    pub rotate_daily: bool,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("access.log"),
+            format: AccessLogFormat::Combined,
+            max_file_size: 100 * 1024 * 1024,
+            rotate_daily: true,
+        }
+    }
+}
+
+/// How access log entries are formatted. Either way, each gives the request's latency, counted the same as the
+/// metrics from when the connection was accepted (for its first request) or the request started coming in, but up to
+/// when the last of the reply went out rather than its head.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// The Combined Log Format used by Apache and nginx, with the latency in seconds appended.
+    #[default]
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Settings for terminating TLS on some of the listeners.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+    /// Which bind addresses terminate TLS, written exactly as they appear in [`ServiceConfig::bind_addresses`].
+    pub listeners: Vec<String>,
+    /// The certificates to serve. The first one is used for clients that don't use SNI, or ask for an unknown name.
+    pub certificates: Vec<TlsCertificateConfig>,
+}
+
+/// A certificate chain and its private key, both PEM encoded and loaded as assets, so they're hot-reloaded on change.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsCertificateConfig {
+    /// The certificate chain, leaf first.
+    pub certificate: PathBuf,
+    /// The private key for the leaf certificate.
+    pub key: PathBuf,
+    /// The server names this certificate is picked for via SNI. `*.example.com` style wildcards are allowed.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
+/// Deserializes a rate, which has to be a finite number above zero. A rate of zero would have a client waiting forever
+/// for its next request.
+pub(crate) fn positive_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let rate = f64::deserialize(deserializer)?;
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(de::Error::custom(format!("requests_per_second must be more than zero, not {rate}")))
+    }
+}
+
+/// Deserializes a burst, which has to be at least one. A bucket that can't hold a single token never lets anything
+/// through.
+pub(crate) fn positive_burst<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let burst = u32::deserialize(deserializer)?;
+    if burst >= 1 {
+        Ok(burst)
+    } else {
+        Err(de::Error::custom("burst must be at least one"))
+    }
+}
+
+/// Deserializes histogram bucket bounds, which have to be finite (`+Inf` is always added), into ascending order
+/// without any repeats.
+fn histogram_buckets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f64>, D::Error> {
+    let mut buckets = Vec::<f64>::deserialize(deserializer)?;
+    if let Some(bad) = buckets.iter().find(|b| !b.is_finite()) {
+        return Err(de::Error::custom(format!("latency_buckets can't hold {bad}")));
+    }
+
+    buckets.sort_by(f64::total_cmp);
+    buckets.dedup();
+    Ok(buckets)
+}
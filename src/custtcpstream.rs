@@ -1,74 +1,70 @@
-use std::{
-    io::{ErrorKind, Read, Write},
-    net::TcpStream,
-};
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 
-pub(crate) struct CustTcpStream {
-    pub(crate) stream: TcpStream,
-}
-
-impl CustTcpStream {
-    pub fn new(stream: TcpStream) -> Self {
-        return Self { stream };
-    }
+/// A TCP (or unix domain socket) stream registered with the [`crate::reactor::IoReactor`], so it only gets polled
+/// when the socket is ready.
+pub(crate) enum CustTcpStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
 }
 
 impl AsyncRead for CustTcpStream {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        _: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.stream.read(buf.initialize_unfilled()) {
-            Ok(v) => {
-                buf.advance(v);
-                return std::task::Poll::Ready(Ok(()));
-            }
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => return std::task::Poll::Pending,
-                _ => return std::task::Poll::Ready(Err(e)),
-            },
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match &mut *self {
+            CustTcpStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            CustTcpStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
         }
     }
 }
 
 impl AsyncWrite for CustTcpStream {
-    fn poll_write(
-        mut self: std::pin::Pin<&mut Self>,
-        _: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        match self.stream.write(buf) {
-            Ok(v) => {
-                return std::task::Poll::Ready(Ok(v));
-            }
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => return std::task::Poll::Pending,
-                _ => return std::task::Poll::Ready(Err(e)),
-            },
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        match &mut *self {
+            CustTcpStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            CustTcpStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match &mut *self {
+            CustTcpStream::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            CustTcpStream::Unix(s) => Pin::new(s).poll_write_vectored(cx, bufs),
         }
     }
 
-    fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
-        _: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        match self.stream.flush() {
-            Ok(_) => {
-                return std::task::Poll::Ready(Ok(()));
-            }
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => return std::task::Poll::Pending,
-                _ => return std::task::Poll::Ready(Err(e)),
-            },
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            CustTcpStream::Tcp(s) => s.is_write_vectored(),
+            #[cfg(unix)]
+            CustTcpStream::Unix(s) => s.is_write_vectored(),
         }
     }
 
-    fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        _: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        return std::task::Poll::Ready(self.stream.shutdown(std::net::Shutdown::Write));
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        match &mut *self {
+            CustTcpStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            CustTcpStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        match &mut *self {
+            CustTcpStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            CustTcpStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
     }
 }
@@ -1,18 +1,66 @@
 use bevy::prelude::*;
+
+use crate::reactor::IoReactor;
+mod access_log;
+pub mod deadline;
+pub mod error;
 pub mod events;
+pub mod info;
+pub mod metrics;
+pub mod middleware;
+pub mod overload;
+pub mod proxy;
 mod request;
 mod service_adapter;
+pub mod shutdown;
+pub mod socket;
+pub mod stream;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tls;
+pub mod websocket;
+use access_log::*;
+use deadline::*;
+use error::*;
 use events::*;
+use metrics::*;
+use middleware::*;
+use overload::*;
 use request::*;
+use shutdown::*;
+use stream::*;
+use tls::*;
+use websocket::*;
 
 #[derive(Default)]
-pub struct HttpRequestPlugin {}
+pub struct HttpRequestPlugin {
+    /// Whether SIGINT and SIGTERM start a graceful shutdown. This takes over the signals for the whole process, so
+    /// only the app's own entry point should turn it on. [`HttpShutdownEvent`] works either way.
+    pub handle_signals: bool,
+}
 
 impl Plugin for HttpRequestPlugin {
     fn build(&self, app: &mut App) {
         app.world.insert_resource(HttpRequestContext::default());
-        app.add_event::<HttpRequestReceivedEvent>()
+        let reactor = IoReactor::start().expect("Couldn't start the IO reactor, can't continue!");
+        let signal = if self.handle_signals { reactor.shutdown_signal() } else { Default::default() };
+        app.world.insert_resource(HttpShutdownState::new(signal));
+        app.world.insert_resource(reactor);
+        app.world.insert_resource(TlsContext::default());
+        app.world.insert_resource(HttpLoadStats::default());
+        app.world.insert_resource(HttpAccessLog::default());
+        app.world.insert_resource(HttpMiddlewareQueue::default());
+        app.world.insert_resource(HttpReplyQueue::default());
+        app.world.insert_resource(HttpResponseHeaders::default());
+        app.world.insert_resource(HttpMetrics::default());
+        app.world.init_resource::<HttpErrorRenderer>();
+        app.add_asset::<TlsPemAsset>()
+            .add_asset_loader(TlsPemLoader())
+            .add_event::<HttpRequestReceivedEvent>()
             .add_event::<HttpRequestReplyEvent>()
+            .add_event::<HttpShutdownEvent>()
+            .add_event::<HttpWebSocketMessageEvent>()
+            .add_event::<HttpWebSocketClosedEvent>()
             .add_stage_before(
                 CoreStage::Update,
                 HttpRequestStages::Listener,
@@ -23,16 +71,67 @@ impl Plugin for HttpRequestPlugin {
                 HttpRequestStages::EventDistro,
                 SystemStage::parallel(),
             )
-            .add_system_to_stage(HttpRequestStages::Listener, http_request_listener_system)
+            .add_stage_before(
+                HttpRequestStages::EventDistro,
+                HttpRequestStages::ResponseFilter,
+                SystemStage::parallel(),
+            )
+            .add_stage_after(
+                HttpRequestStages::EventDistro,
+                HttpRequestStages::Middleware,
+                SystemStage::parallel(),
+            )
+            .add_system_to_stage(HttpRequestStages::Listener, tls_certificate_reloader)
+            .add_system_to_stage(HttpRequestStages::Listener, http_shutdown_system)
+            .add_system_to_stage(HttpRequestStages::Listener, http_response_headers_config_system)
+            .add_system_to_stage(
+                HttpRequestStages::Listener,
+                http_request_listener_system
+                    .after(tls_certificate_reloader)
+                    .after(http_shutdown_system)
+                    .after(http_response_headers_config_system),
+            )
+            .add_system_to_stage(
+                HttpRequestStages::ResponseFilter,
+                http_reply_collect_system.label(HttpFilterSystems::Collect),
+            )
+            .add_system_to_stage(
+                HttpRequestStages::ResponseFilter,
+                http_response_headers_system
+                    .label(HttpFilterSystems::Headers)
+                    .after(HttpFilterSystems::Collect),
+            )
             .add_system_to_stage(HttpRequestStages::EventDistro, http_request_events_system)
-            .add_system_to_stage(CoreStage::PostUpdate, http_finalizer);
+            .add_system_to_stage(HttpRequestStages::EventDistro, http_websocket_system)
+            .add_system_to_stage(
+                HttpRequestStages::Middleware,
+                http_middleware_dispatch_system.label(HttpFilterSystems::Dispatch),
+            )
+            // Ahead of any filters added by other plugins, so the metrics are never rate limited or the like.
+            .add_request_filter(http_metrics_system)
+            .add_system_to_stage(CoreStage::PostUpdate, http_response_stream_system)
+            .add_system_to_stage(CoreStage::PostUpdate, http_deadline_system)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                http_finalizer
+                    .after(http_response_stream_system)
+                    .after(http_deadline_system),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, http_idle_connection_system);
     }
 }
 
+/// The stages the HTTP plugin adds, all of which run before [`CoreStage::Update`].
 #[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
-enum HttpRequestStages {
+pub enum HttpRequestStages {
     /// Handles the actual http listening.
     Listener,
+    /// Runs response filters, added with [`middleware::HttpFilterAppExt::add_response_filter`], over the replies sent
+    /// since the last frame before they go out.
+    ResponseFilter,
     /// Distributes request handling events.
     EventDistro,
+    /// Runs request filters, added with [`middleware::HttpFilterAppExt::add_request_filter`], over incoming
+    /// requests before they're raised as [`events::HttpRequestReceivedEvent`]s to be routed.
+    Middleware,
 }
@@ -0,0 +1,406 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use chrono::{DateTime, Local, NaiveDate};
+use http::{
+    header::{REFERER, USER_AGENT},
+    Method, Request, StatusCode, Uri, Version,
+};
+use hyper::body::{Bytes, HttpBody};
+use log::error;
+
+use super::{
+    service_adapter::{HttpReplyResult, RequestStarted},
+    socket::HttpAddr,
+};
+use crate::config::{AccessLogConfig, AccessLogFormat};
+
+/// What the access log needs to know about a request, filled in as it's handled.
+pub(in crate::http) struct HttpAccessRecord {
+    request_id: String,
+    peer_addr: HttpAddr,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    received_at: DateTime<Local>,
+    /// When the connection was accepted, for its first request, or when the request started coming in otherwise.
+    started: Instant,
+    status: Option<StatusCode>,
+    body_bytes: Option<u64>,
+    /// When the reply (or the head of a streamed one) went out.
+    replied: Option<Instant>,
+}
+
+impl HttpAccessRecord {
+    pub fn new(request: &Request<Bytes>, request_id: String, peer_addr: HttpAddr) -> Self {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        };
+
+        Self {
+            request_id,
+            peer_addr,
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            version: request.version(),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            received_at: Local::now(),
+            started: request.extensions().get::<RequestStarted>().map_or_else(Instant::now, |s| s.0),
+            status: None,
+            body_bytes: None,
+            replied: None,
+        }
+    }
+
+    /// Notes down the reply. The size of a streamed body isn't known up front, and is filled in when the request's done.
+    pub fn replied(&mut self, result: &HttpReplyResult) {
+        if let Ok(response) = result {
+            self.status = Some(response.status());
+            self.body_bytes = response.body().size_hint().exact();
+        }
+        self.replied = Some(Instant::now());
+    }
+
+    /// The status replied with, if the reply went out.
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status
+    }
+
+    /// How long the request took to be replied to, if it was, counted from when it was started.
+    pub fn reply_latency(&self) -> Option<Duration> {
+        self.replied.map(|r| r.duration_since(self.started))
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// The client's IP address, or `unix:` for a unix domain socket.
+    fn host(&self) -> String {
+        self.peer_addr.ip().map_or_else(|| self.peer_addr.to_string(), |ip| ip.to_string())
+    }
+
+    /// The request target as the client sent it, without the scheme and authority HTTP/2 adds.
+    fn target(&self) -> &str {
+        self.uri.path_and_query().map_or("/", |p| p.as_str())
+    }
+
+    /// Formats the entry as a Combined Log Format line, with the latency in seconds and the request ID tacked on the end.
+    fn combined(&self, body_bytes: Option<u64>, latency: Duration) -> String {
+        let quoted = |v: &Option<String>| match v {
+            Some(v) => v.replace('\\', "\\\\").replace('"', "\\\""),
+            None => "-".to_string(),
+        };
+
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\" {:.3} {}",
+            self.host(),
+            self.received_at.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.target().replace('"', "%22"),
+            self.version,
+            self.status.map_or("-".to_string(), |s| s.as_u16().to_string()),
+            body_bytes.map_or("-".to_string(), |b| b.to_string()),
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            latency.as_secs_f64(),
+            self.request_id,
+        )
+    }
+
+    /// Formats the entry as a single line JSON object.
+    fn json(&self, body_bytes: Option<u64>, latency: Duration) -> String {
+        let string = |v: Option<&str>| match v {
+            Some(v) => json_string(v),
+            None => "null".to_string(),
+        };
+
+        format!(
+            "{{\"time\":{},\"remote_addr\":{},\"method\":{},\"path\":{},\"protocol\":{},\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\"latency_ms\":{:.3},\"request_id\":{}}}",
+            json_string(&self.received_at.to_rfc3339()),
+            json_string(&self.host()),
+            json_string(self.method.as_str()),
+            json_string(self.target()),
+            json_string(&format!("{:?}", self.version)),
+            self.status.map_or("null".to_string(), |s| s.as_u16().to_string()),
+            body_bytes.map_or("null".to_string(), |b| b.to_string()),
+            string(self.referer.as_deref()),
+            string(self.user_agent.as_deref()),
+            latency.as_secs_f64() * 1000.0,
+            json_string(&self.request_id),
+        )
+    }
+}
+
+fn json_string(v: &str) -> String {
+    let mut out = String::with_capacity(v.len() + 2);
+    out.push('"');
+    for c in v.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// An open access log file, rotated by size and/or day.
+struct AccessLogFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl AccessLogFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file: BufWriter::new(file),
+            size,
+            opened_on: Local::now().date_naive(),
+        })
+    }
+
+    /// Moves the current file aside, named after the time it's rotated at, and starts a fresh one.
+    fn rotate(&mut self, now: DateTime<Local>) -> io::Result<()> {
+        self.file.flush()?;
+
+        let stamp = now.format("%Y%m%d-%H%M%S");
+        let mut rotated = PathBuf::from(format!("{}.{stamp}", self.path.display()));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{stamp}.{n}", self.path.display()));
+            n += 1;
+        }
+
+        fs::rename(&self.path, rotated)?;
+        *self = Self::open(self.path.clone())?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str, cfg: &AccessLogConfig, now: DateTime<Local>) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let new_day = cfg.rotate_daily && now.date_naive() != self.opened_on;
+        let too_big = cfg.max_file_size > 0 && self.size > 0 && self.size + len > cfg.max_file_size;
+
+        if new_day || too_big {
+            self.rotate(now)?;
+        }
+
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+/// Writes the access log, if one's configured. The file is opened on the first request.
+///
+/// Requests are logged once they're finalized, apart from those turned away for a body that's too large or the request
+/// queue being full, which are logged as they're turned away. Connections turned away for being over the connection
+/// limit never get as far as a request, so aren't logged.
+#[derive(Resource, Default)]
+pub(in crate::http) struct HttpAccessLog {
+    file: Option<AccessLogFile>,
+    /// Set if the log couldn't be opened, so we don't keep trying on every request.
+    failed: bool,
+}
+
+impl HttpAccessLog {
+    /// Logs a finished request. `streamed_bytes` is the size of the body if it was streamed.
+    pub fn write(&mut self, cfg: &Option<AccessLogConfig>, record: &HttpAccessRecord, streamed_bytes: Option<u64>) {
+        let Some(cfg) = cfg else {
+            return;
+        };
+
+        if self.file.is_none() && !self.failed {
+            match AccessLogFile::open(cfg.path.clone()) {
+                Ok(file) => self.file = Some(file),
+                Err(e) => {
+                    error!("Couldn't open the access log {:?}, requests won't be logged: {e}", cfg.path);
+                    self.failed = true;
+                }
+            }
+        }
+
+        let Some(file) = &mut self.file else {
+            return;
+        };
+
+        let body_bytes = streamed_bytes.or(record.body_bytes);
+        // From the same start as the metrics, but up to the last of the body rather than the head.
+        let latency = record.started.elapsed();
+        let line = match cfg.format {
+            AccessLogFormat::Combined => record.combined(body_bytes, latency),
+            AccessLogFormat::Json => record.json(body_bytes, latency),
+        };
+
+        if let Err(e) = file.write_line(&line, cfg, Local::now()) {
+            error!("Couldn't write to the access log {:?}: {e}", cfg.path);
+        }
+    }
+
+    /// Pushes out everything written so far.
+    pub fn flush(&mut self) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.file.flush() {
+                error!("Couldn't write to the access log {:?}: {e}", file.path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use chrono::Duration as DateDuration;
+
+    use super::*;
+
+    fn record(request: Request<Bytes>) -> HttpAccessRecord {
+        let mut record = HttpAccessRecord::new(&request, "req-1".to_string(), HttpAddr::Tcp(SocketAddr::from(([192, 0, 2, 1], 5000))));
+        record.replied(&Ok(hyper::Response::new(hyper::Body::from("hi"))));
+        record
+    }
+
+    /// A directory of its own for each test, emptied out first.
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bevyblog-access-log-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files_in(dir: &PathBuf) -> Vec<String> {
+        let mut files: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        files
+    }
+
+    fn size_limited(max_file_size: u64) -> AccessLogConfig {
+        AccessLogConfig { max_file_size, rotate_daily: false, ..Default::default() }
+    }
+
+    #[test]
+    fn escapes_combined_log_fields() {
+        let request = Request::get(r#"/say/"hi"?q=1"#)
+            .header(REFERER, r#"https://example.com/"quoted"\"#)
+            .header(USER_AGENT, r#"agent "with" \backslashes\"#)
+            .body(Bytes::new())
+            .unwrap();
+        let line = record(request).combined(Some(2), Duration::from_millis(1500));
+
+        assert!(line.starts_with("192.0.2.1 - - ["), "{line}");
+        assert!(line.contains(r#""GET /say/%22hi%22?q=1 HTTP/1.1" 200 2 "#), "{line}");
+        assert!(line.contains(r#" "https://example.com/\"quoted\"\\" "#), "{line}");
+        assert!(line.ends_with(r#" "agent \"with\" \\backslashes\\" 1.500 req-1"#), "{line}");
+    }
+
+    #[test]
+    fn leaves_missing_combined_log_fields_as_dashes() {
+        let request = Request::get("/").body(Bytes::new()).unwrap();
+        let line = record(request).combined(None, Duration::ZERO);
+
+        assert!(line.ends_with(r#""GET / HTTP/1.1" 200 - "-" "-" 0.000 req-1"#), "{line}");
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string(r#"a "quote" and \ slash"#), r#""a \"quote\" and \\ slash""#);
+        assert_eq!(json_string("line\nbreak\r\ttab"), r#""line\nbreak\r\ttab""#);
+        assert_eq!(json_string("\u{0}\u{1b}\u{7f}"), r#""\u0000\u001b\u007f""#);
+        assert_eq!(json_string("ünïcödé"), r#""ünïcödé""#);
+    }
+
+    #[test]
+    fn writes_json_entries_on_one_line() {
+        let request = Request::get("/").header(USER_AGENT, "tabbed\t\"agent\"").body(Bytes::new()).unwrap();
+        let line = record(request).json(Some(2), Duration::from_millis(3));
+
+        assert!(!line.contains('\n'));
+        assert!(line.contains(r#""user_agent":"tabbed\t\"agent\"""#), "{line}");
+        assert!(line.contains(r#""referer":null"#), "{line}");
+        assert!(line.contains(r#""status":200,"bytes":2"#), "{line}");
+    }
+
+    #[test]
+    fn rotates_files_that_would_grow_too_big() {
+        let dir = log_dir("size");
+        let cfg = size_limited(16);
+        let now = Local::now();
+        let mut file = AccessLogFile::open(dir.join("access.log")).unwrap();
+
+        file.write_line("first entry", &cfg, now).unwrap();
+        file.write_line("second entry", &cfg, now).unwrap();
+        // Rotated within the same second as the last, so it needs a suffix to keep from clobbering it.
+        file.write_line("third entry", &cfg, now).unwrap();
+        file.file.flush().unwrap();
+
+        let stamp = now.format("%Y%m%d-%H%M%S");
+        assert_eq!(files_in(&dir), ["access.log".to_string(), format!("access.log.{stamp}"), format!("access.log.{stamp}.1")]);
+        assert_eq!(fs::read_to_string(dir.join(format!("access.log.{stamp}"))).unwrap(), "first entry\n");
+        assert_eq!(fs::read_to_string(dir.join(format!("access.log.{stamp}.1"))).unwrap(), "second entry\n");
+        assert_eq!(fs::read_to_string(dir.join("access.log")).unwrap(), "third entry\n");
+    }
+
+    #[test]
+    fn never_rotates_an_empty_file() {
+        let dir = log_dir("empty");
+        let mut file = AccessLogFile::open(dir.join("access.log")).unwrap();
+
+        file.write_line("an entry longer than the limit", &size_limited(8), Local::now()).unwrap();
+        file.file.flush().unwrap();
+
+        assert_eq!(files_in(&dir), ["access.log"]);
+        assert_eq!(fs::read_to_string(dir.join("access.log")).unwrap(), "an entry longer than the limit\n");
+    }
+
+    #[test]
+    fn rotates_daily() {
+        let dir = log_dir("daily");
+        let cfg = AccessLogConfig { max_file_size: 0, rotate_daily: true, ..Default::default() };
+        let mut file = AccessLogFile::open(dir.join("access.log")).unwrap();
+
+        file.write_line("today", &cfg, Local::now()).unwrap();
+        let tomorrow = Local::now() + DateDuration::days(1);
+        file.write_line("tomorrow", &cfg, tomorrow).unwrap();
+        file.file.flush().unwrap();
+
+        let rotated = format!("access.log.{}", tomorrow.format("%Y%m%d-%H%M%S"));
+        assert_eq!(files_in(&dir), ["access.log".to_string(), rotated.clone()]);
+        assert_eq!(fs::read_to_string(dir.join(rotated)).unwrap(), "today\n");
+    }
+}
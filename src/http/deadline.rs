@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use http::StatusCode;
+use log::warn;
+
+use super::{error::HttpHandlerError, events::HttpRequestReplyEvent, request::HttpRequestComponent};
+
+/// When a request has to be replied to by. Every request entity gets one, set from
+/// [`crate::config::LimitsConfig::request_timeout_secs`]. Routing may change the timeout, and should record which
+/// route the request went to, so a handler that never replies can be tracked down.
+#[derive(Component, Debug)]
+pub struct HttpRequestDeadline {
+    received: Instant,
+    timeout: Duration,
+    route: Option<String>,
+    /// Set once the 504 has been sent, so it's only sent the once.
+    timed_out: bool,
+}
+
+impl HttpRequestDeadline {
+    pub(in crate::http) fn new(timeout: Duration) -> Self {
+        Self {
+            received: Instant::now(),
+            timeout,
+            route: None,
+            timed_out: false,
+        }
+    }
+
+    /// When the request times out.
+    pub fn expires_at(&self) -> Instant {
+        self.received + self.timeout
+    }
+
+    /// Sets how long the request may take, counted from when it was received.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The route the request was handed to, if any.
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    pub fn set_route(&mut self, route: impl Into<String>) {
+        self.route = Some(route.into());
+    }
+}
+
+/// Replies 504 (Gateway Timeout) to requests nobody replied to in time. The reply is rendered by the
+/// [`super::error::HttpErrorRenderer`] and goes through the response filters like any other.
+pub(in crate::http) fn http_deadline_system(
+    mut req_comp: Query<(Entity, &HttpRequestComponent, &mut HttpRequestDeadline, &Name)>,
+    mut reply_events: EventWriter<HttpRequestReplyEvent>,
+) {
+    let now = Instant::now();
+
+    for (ent, comp, mut deadline, name) in req_comp.iter_mut() {
+        if comp.txres.is_none() || deadline.timed_out || deadline.expires_at() > now {
+            continue;
+        }
+
+        match deadline.route() {
+            Some(route) => warn!("\"{}\" timed out waiting on route {route}, replying 504.", name.as_str()),
+            None => warn!("\"{}\" timed out without being routed, replying 504.", name.as_str()),
+        }
+
+        deadline.timed_out = true;
+        let error = HttpHandlerError::new(StatusCode::GATEWAY_TIMEOUT);
+        reply_events.send(HttpRequestReplyEvent::new(Err(Box::new(error)), ent));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{error::Error, fmt::Display, sync::Arc};
+
+use bevy::prelude::*;
+use http::{header::HeaderName, HeaderMap, HeaderValue, Method, Response, StatusCode, Uri};
+use hyper::Body;
+
+/// An error for handlers to reply with, carrying the status to reply with and, optionally, a message for the client.
+/// It's rendered into an error page by the [`HttpErrorRenderer`], and the error it wraps is only ever logged.
+///
+/// Any other error a handler replies with is treated as a 500 (Internal Server Error) with no message. Errors with a
+/// source are logged as they're replied with; ones without are expected to have been logged by whoever raised them.
+#[derive(Debug)]
+pub struct HttpHandlerError {
+    status: StatusCode,
+    message: Option<String>,
+    headers: HeaderMap,
+    source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl HttpHandlerError {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            message: None,
+            headers: HeaderMap::new(),
+            source: None,
+        }
+    }
+
+    /// A 500 (Internal Server Error) caused by `source`.
+    pub fn internal(source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR).with_source(source)
+    }
+
+    /// Sets a message to show the client. Don't put anything in it they shouldn't see.
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// Sets a header on the error page, like `Retry-After` or `Allow`, over any the renderer sets.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Sets the error behind this one, which is logged but never shown to the client.
+    pub fn with_source(mut self, source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Takes whatever a handler replied with, making a 500 of anything that isn't already an [`HttpHandlerError`].
+    pub(in crate::http) fn from_reply(error: Box<dyn Error + Send + Sync>) -> Self {
+        match error.downcast::<Self>() {
+            Ok(error) => *error,
+            Err(error) => Self::internal(error),
+        }
+    }
+}
+
+impl Display for HttpHandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let Some(source) = &self.source {
+            write!(f, " caused by {source}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for HttpHandlerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+/// What an error page is rendered from.
+pub struct HttpErrorContext<'a> {
+    pub status: StatusCode,
+    /// The message for the client, from [`HttpHandlerError::with_message`].
+    pub message: Option<&'a str>,
+    /// `-` for errors the connection raises itself, which never learns the request's ID, like the 500 for a request
+    /// dropped without a reply.
+    pub request_id: &'a str,
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+}
+
+/// Renders every error page, whether a handler replied with an error or the server turned the request away itself
+/// (unrouted requests, rate limits, timeouts, overload and the like). Insert one of your own to replace the default
+/// plain text page, which gives the status, the message if there is one, and the request ID so the client can quote
+/// it back to us. The response always goes out with the error's status, whatever the renderer sets.
+///
+/// Connections take a copy of the renderer when they're accepted, for errors raised before a request reaches the app,
+/// so insert yours before the first frame.
+#[derive(Resource, Clone)]
+pub struct HttpErrorRenderer(Arc<dyn Fn(&HttpErrorContext) -> Response<Body> + Send + Sync>);
+
+impl HttpErrorRenderer {
+    pub fn new(render: impl Fn(&HttpErrorContext) -> Response<Body> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(render))
+    }
+
+    pub fn render(&self, ctx: &HttpErrorContext) -> Response<Body> {
+        let mut response = (self.0)(ctx);
+        *response.status_mut() = ctx.status;
+        response
+    }
+
+    /// Renders the page for an error, with the headers it carries.
+    pub(in crate::http) fn render_error(&self, error: &HttpHandlerError, request_id: &str, method: &Method, uri: &Uri) -> Response<Body> {
+        let mut response = self.render(&HttpErrorContext {
+            status: error.status,
+            message: error.message(),
+            request_id,
+            method,
+            uri,
+        });
+        for (name, value) in &error.headers {
+            response.headers_mut().insert(name, value.clone());
+        }
+        response
+    }
+}
+
+impl Default for HttpErrorRenderer {
+    fn default() -> Self {
+        Self::new(|ctx| {
+            let mut text = format!("{} {}.", ctx.status.as_u16(), ctx.status.canonical_reason().unwrap_or("Error"));
+            if let Some(message) = ctx.message {
+                text.push('\n');
+                text.push_str(message);
+            }
+            if ctx.request_id != "-" {
+                text.push_str("\nRequest ID: ");
+                text.push_str(ctx.request_id);
+            }
+            Response::new(Body::from(text))
+        })
+    }
+}
@@ -1,79 +1,326 @@
-use log::{error, info};
+use log::{debug, error, info, warn};
 use std::{
     error::Error,
     fmt::Display,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use bevy::prelude::*;
-use http::{Request, Response};
-use hyper::Body;
+use http::{Request, Response, StatusCode};
+use hyper::{
+    body::{Body, Bytes, Sender},
+    upgrade::OnUpgrade,
+};
 
-use super::request::HttpRequestComponent;
+use super::access_log::{HttpAccessLog, HttpAccessRecord};
+use super::deadline::HttpRequestDeadline;
+use super::error::{HttpErrorRenderer, HttpHandlerError};
+use super::metrics::{HttpMetrics, HttpMetricsRoute};
+use super::middleware::{HttpMiddlewareQueue, HttpPendingReply, HttpReplyQueue, HttpResponseHeaders};
+use super::info::{request_id, tag_reply, HttpRequestId, HttpRequestInfo};
+use super::overload::{service_unavailable, HttpLoadStats};
+use super::proxy::{resolve_client_addr, HttpClientAddr, ProxyProtocolPeer};
+use super::request::{HttpConnectionComponent, HttpRequestComponent, HttpRequestEntityBundle};
+use super::service_adapter::{BodyTooLarge, HttpReplyResult};
+use super::socket::HttpAddr;
+use super::stream::HttpResponseStream;
+use super::websocket::{handshake_response, HttpWebSocket};
+use crate::config::ServiceConfig;
 
 /// Event that, when raised, contains information about an incoming HTTP request, namely it's body and attached entity.
+/// The request body has already been read in full.
 #[derive(Debug)]
 pub struct HttpRequestReceivedEvent {
-    pub body: Arc<Request<Body>>,
+    pub body: Arc<Request<Bytes>>,
     pub ent: Entity,
+    /// The address of the peer that sent the request, which may be a proxy.
+    pub peer_addr: HttpAddr,
+    /// The address of the client behind the request, as worked out from the trusted proxies it came through.
+    /// This is the same as `peer_addr` if it didn't come through any.
+    pub client_addr: HttpAddr,
+    /// The same as the [`HttpRequestInfo`] on the request entity.
+    pub info: HttpRequestInfo,
 }
 
 /// Event that, when raised, will be handled to reply to the specified HTTP request.
 #[derive(Debug)]
 pub struct HttpRequestReplyEvent {
-    body: Mutex<Result<Response<Body>, Box<dyn Error + Send + Sync>>>,
+    body: Mutex<HttpReplyResult>,
+    stream: Mutex<Option<Sender>>,
+    websocket: bool,
     ent: Entity,
 }
 
 impl HttpRequestReplyEvent {
     /// Constructs a new HttpRequestReplyEvent, given a response and the request to reply to.
-    pub fn new(result: Result<Response<Body>, Box<dyn Error + Send + Sync>>, request: Entity) -> Self {
+    /// Errors are rendered into error pages, see [`super::error::HttpHandlerError`].
+    pub fn new(result: HttpReplyResult, request: Entity) -> Self {
         HttpRequestReplyEvent {
             body: Mutex::new(result),
+            stream: Mutex::new(None),
+            websocket: false,
             ent: request,
         }
     }
+
+    /// Constructs a reply that only sends the response head for now.
+    /// Once it's handled, the request entity gets a [`HttpResponseStream`] the body can be written through.
+    pub fn streaming(head: Response<()>, request: Entity) -> Self {
+        let (sender, body) = Body::channel();
+        let (parts, ()) = head.into_parts();
+        HttpRequestReplyEvent {
+            body: Mutex::new(Ok(Response::from_parts(parts, body))),
+            stream: Mutex::new(Some(sender)),
+            websocket: false,
+            ent: request,
+        }
+    }
+
+    /// Constructs a reply accepting a WebSocket upgrade, or None if the request didn't ask for one.
+    /// Once it's handled, the request entity gets a [`HttpWebSocket`] to talk to the client through.
+    pub fn websocket(request_data: &Request<Bytes>, request: Entity) -> Option<Self> {
+        let response = handshake_response(request_data)?;
+        Some(HttpRequestReplyEvent {
+            body: Mutex::new(Ok(response)),
+            stream: Mutex::new(None),
+            websocket: true,
+            ent: request,
+        })
+    }
 }
 
 pub(in crate::http) fn http_request_events_system(
-    req_comp: Query<(Entity, &HttpRequestComponent)>,
-    mut recv_ev_writer: EventWriter<HttpRequestReceivedEvent>,
-    mut reply_ev_reader: EventReader<HttpRequestReplyEvent>,
+    mut conn_comp: Query<(Entity, &mut HttpConnectionComponent)>,
+    mut req_comp: Query<&mut HttpRequestComponent>,
+    mut middleware: ResMut<HttpMiddlewareQueue>,
+    mut replies: ResMut<HttpReplyQueue>,
+    cfg: Res<ServiceConfig>,
+    renderer: Res<HttpErrorRenderer>,
+    headers: Res<HttpResponseHeaders>,
+    mut access_log: ResMut<HttpAccessLog>,
+    mut stats: ResMut<HttpLoadStats>,
+    mut commands: Commands,
 ) {
-    for (ent, comp) in req_comp.iter() {
-        let l = comp.rxreq.lock().unwrap();
+    let timeout = Duration::from_secs(cfg.limits.request_timeout_secs);
+    let mut queued = req_comp.iter().filter(|r| r.txres.is_some()).count();
+
+    for (conn_ent, mut conn) in conn_comp.iter_mut() {
+        let conn = &mut *conn;
+        let l = conn.rxreq.lock().unwrap();
+
+        while let Ok((mut body, txres)) = l.try_recv() {
+            stats.requests_received += 1;
+            conn.last_active = Instant::now();
+
+            // A PROXY protocol header stands in for the connection's own address.
+            let peer = match body.extensions().get::<ProxyProtocolPeer>() {
+                Some(ProxyProtocolPeer(addr)) => HttpAddr::Tcp(*addr),
+                None => conn.peer_addr.clone(),
+            };
+            let id = request_id(body.headers(), &peer, &cfg.proxy.trusted_proxies);
+
+            let client = resolve_client_addr(&peer, body.headers(), &cfg.proxy);
+            let rejection = if body.extensions().get::<BodyTooLarge>().is_some() {
+                warn!(
+                    "[{id}] Refusing a request for \"{}\" with a body over {} bytes.",
+                    body.uri(),
+                    cfg.limits.max_request_body_size
+                );
+                Some(HttpHandlerError::new(StatusCode::PAYLOAD_TOO_LARGE))
+            } else if queued >= cfg.limits.max_queued_requests {
+                warn!("[{id}] Turning away a request for \"{}\", already at {queued} queued requests.", body.uri());
+                stats.requests_rejected += 1;
+                Some(service_unavailable(cfg.limits.retry_after_secs))
+            } else {
+                None
+            };
+
+            if let Some(error) = rejection {
+                let mut record = HttpAccessRecord::new(&body, id.clone(), client);
+                let mut response = renderer.render_error(&error, &id, body.method(), body.uri());
+                // There's no request entity to go through the response filters, so only the configured headers apply.
+                headers.apply(&mut response);
+                let mut reply = Ok(response);
+                tag_reply(&mut reply, &id);
+                record.replied(&reply);
+                access_log.write(&cfg.access_log, &record, None);
+                let _ = txres.send(reply);
+                continue;
+            }
+
+            queued += 1;
+            conn.served += 1;
+            conn.in_flight += 1;
+
+            let info = HttpRequestInfo::new(
+                id.clone(),
+                peer.clone(),
+                conn.local_addr.clone(),
+                conn.listener.clone(),
+                conn.scheme,
+                conn.accepted_at,
+            );
+            body.extensions_mut().insert(HttpRequestId(id.clone()));
+            // This can't be shared with handlers, so it's kept back for if they accept a WebSocket.
+            let upgrade = body.extensions_mut().remove::<OnUpgrade>();
+
+            let ent = commands
+                .spawn(HttpRequestEntityBundle {
+                    request: HttpRequestComponent {
+                        connection: conn_ent,
+                        txres: Some(txres),
+                        record: HttpAccessRecord::new(&body, id.clone(), client.clone()),
+                        upgrade,
+                    },
+                    deadline: HttpRequestDeadline::new(timeout),
+                    client: HttpClientAddr(client.clone()),
+                    info: info.clone(),
+                    name: Name::new(format!(
+                        "HTTP Request {id} (#{} {} via {})",
+                        conn.served, client, conn.local_addr
+                    )),
+                })
+                .id();
 
-        if let Ok(body) = l.try_recv() {
             let uri = body.uri();
-            info!("Sent off received event for {ent:?} at URI \"{uri:?}\".");
-            recv_ev_writer.send(HttpRequestReceivedEvent {
-                body: Arc::new(body),
-                ent,
-            });
+            info!("[{id}] Sent off {ent:?} at URI \"{uri:?}\" to the request filters.");
+            middleware.push(ent, body, peer, client, info);
+        }
+    }
+
+    for reply in replies.replies.drain(..) {
+        if let Ok(mut comp) = req_comp.get_mut(reply.ent) {
+            let id = comp.record.request_id().to_string();
+            info!("[{id}] Got a reply, trying to send it!");
+            let mut result = reply.result;
+
+            let Some(txres) = comp.txres.take() else {
+                error!("[{id}] Tried to reply to a request with id {:?} after it's already done.", reply.ent);
+                continue;
+            };
+            queued = queued.saturating_sub(1);
+            tag_reply(&mut result, &id);
+            comp.record.replied(&result);
+
+            if let Ok((_, mut conn)) = conn_comp.get_mut(comp.connection) {
+                conn.last_active = Instant::now();
+            }
 
+            if txres.send(result).is_err() {
+                error!("[{id}] Tried to reply to a request with id {:?} after its connection closed.", reply.ent);
+            } else if let Some(sender) = reply.stream {
+                commands.entity(reply.ent).insert(HttpResponseStream::new(sender));
+            } else if reply.websocket {
+                match comp.upgrade.take() {
+                    Some(upgrade) => {
+                        info!("[{id}] Accepted a WebSocket.");
+                        let socket = HttpWebSocket::spawn(upgrade, cfg.limits.max_websocket_message_size);
+                        commands.entity(reply.ent).insert(socket);
+                    }
+                    None => error!("[{id}] Accepted a WebSocket on a connection that can't be upgraded."),
+                }
+            }
         }
     }
 
+    stats.queued_requests = queued;
+}
+
+/// Takes the replies sent since the last frame, queuing them up for the response filters. Errors are rendered into
+/// error pages here, so the filters only ever see responses.
+pub(in crate::http) fn http_reply_collect_system(
+    req_comp: Query<&HttpRequestComponent>,
+    mut reply_ev_reader: EventReader<HttpRequestReplyEvent>,
+    renderer: Res<HttpErrorRenderer>,
+    mut queue: ResMut<HttpReplyQueue>,
+) {
     for i in reply_ev_reader.iter() {
-        if let Ok((_, comp)) = req_comp.get(i.ent) {
-            info!("Got a reply, trying to send it!");
-            let mut bodylock = i.body.lock().unwrap();
-            let mut err: Result<Response<Body>, Box<dyn Error + Send + Sync>> = Err(Box::new(TakenError()));
-            std::mem::swap(&mut *bodylock, &mut err);
-            if comp.task.is_finished() || comp.txres.try_send(err).is_err() {
-                error!(
-                    "Tried to reply to a request with id {:?} after it's already done.",
-                    i.ent
-                );
+        let Ok(comp) = req_comp.get(i.ent) else {
+            continue;
+        };
 
-                continue;
+        let mut result: HttpReplyResult = Err(Box::new(TakenError()));
+        std::mem::swap(&mut *i.body.lock().unwrap(), &mut result);
+
+        if let Err(e) = result {
+            let error = HttpHandlerError::from_reply(e);
+            let id = comp.record.request_id();
+            let (method, uri) = (comp.record.method(), comp.record.uri());
+            // Whatever raised an error without a cause has already said why, if it's worth saying.
+            match error.source() {
+                Some(_) if error.status().is_server_error() => error!("[{id}] {method} \"{uri}\" failed with {error}"),
+                Some(_) => warn!("[{id}] {method} \"{uri}\" failed with {error}"),
+                None => debug!("[{id}] {method} \"{uri}\" failed with {error}"),
             }
+
+            result = Ok(renderer.render_error(&error, id, method, uri));
         }
+
+        queue.replies.push(HttpPendingReply {
+            ent: i.ent,
+            method: comp.record.method().clone(),
+            uri: comp.record.uri().clone(),
+            result,
+            stream: i.stream.lock().unwrap().take(),
+            websocket: i.websocket,
+        });
     }
 }
 
-pub(in crate::http) fn http_finalizer(req_comp: Query<(Entity, &HttpRequestComponent, &Name)>, mut cmds: Commands) {
-    for (e, comp, name) in req_comp.iter() {
+pub(in crate::http) fn http_finalizer(
+    mut conn_comp: Query<(Entity, &mut HttpConnectionComponent, &Name)>,
+    req_comp: Query<(
+        Entity,
+        &HttpRequestComponent,
+        Option<&HttpResponseStream>,
+        Option<&HttpWebSocket>,
+        Option<&HttpMetricsRoute>,
+        &Name,
+    )>,
+    cfg: Res<ServiceConfig>,
+    mut access_log: ResMut<HttpAccessLog>,
+    mut metrics: ResMut<HttpMetrics>,
+    mut cmds: Commands,
+) {
+    let buckets = cfg.metrics.as_ref().map_or(&[][..], |m| &m.latency_buckets);
+
+    for (e, comp, stream, websocket, route, name) in req_comp.iter() {
+        // A WebSocket has taken over the connection, and lives on until it's closed.
+        if websocket.is_some_and(|w| !w.is_closed()) {
+            continue;
+        }
+
+        // Replied to (with any streamed body sent in full), or orphaned by its connection (or the client) going away.
+        let connection = conn_comp
+            .get_mut(comp.connection)
+            .ok()
+            .filter(|(_, c, _)| !c.task.is_finished());
+        let replied = comp.txres.as_ref().is_none_or(|t| t.is_closed()) && stream.is_none_or(|s| s.is_closed());
+
+        if connection.is_some() && !replied {
+            continue;
+        }
+
+        if let Some((_, mut conn, _)) = connection {
+            conn.in_flight = conn.in_flight.saturating_sub(1);
+            conn.last_active = Instant::now();
+        }
+
+        access_log.write(&cfg.access_log, &comp.record, stream.map(|s| s.bytes_sent()));
+        metrics.record_request(
+            route.map(|r| r.0.as_str()),
+            comp.record.method(),
+            comp.record.status(),
+            comp.record.reply_latency(),
+            buckets,
+        );
+        cmds.entity(e).despawn();
+        info!("Finalizing \"{}\"", name.as_str());
+    }
+    access_log.flush();
+
+    for (e, comp, name) in conn_comp.iter() {
         if comp.task.is_finished() {
             cmds.entity(e).despawn();
             info!("Finalizing \"{}\"", name.as_str());
@@ -81,6 +328,25 @@ pub(in crate::http) fn http_finalizer(req_comp: Query<(Entity, &HttpRequestCompo
     }
 }
 
+/// Closes connections that have sat idle, with no outstanding requests, for longer than the keep-alive timeout.
+/// Despawning the connection drops its task, which closes the socket.
+pub(in crate::http) fn http_idle_connection_system(
+    conn_comp: Query<(Entity, &HttpConnectionComponent, &Name)>,
+    cfg: Res<ServiceConfig>,
+    mut cmds: Commands,
+) {
+    let timeout = Duration::from_secs(cfg.keep_alive.idle_timeout_secs);
+
+    for (e, comp, name) in conn_comp.iter() {
+        // A request still having its body read hasn't reached us yet, but the connection's far from idle.
+        let busy = comp.in_flight > 0 || comp.rxreq.lock().unwrap().pending() > 0;
+        if !busy && comp.last_active.elapsed() > timeout && !comp.task.is_finished() {
+            cmds.entity(e).despawn();
+            info!("Closing idle \"{}\"", name.as_str());
+        }
+    }
+}
+
 /// A fallback error that is used should a bug occur in reply handling that causes us to attempt to reply with bad data.
 #[derive(Debug)]
 pub struct TakenError();
@@ -0,0 +1,105 @@
+use std::fmt::Display;
+
+use bevy::prelude::*;
+use chrono::{DateTime, Local};
+use http::{header::HeaderName, HeaderMap, HeaderValue, Request};
+use uuid::Uuid;
+
+use super::{proxy::is_trusted, service_adapter::HttpReplyResult, socket::HttpAddr};
+use crate::config::TrustedProxy;
+
+/// Whether a request came in over plain HTTP or over TLS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HttpScheme {
+    Http,
+    Https,
+}
+
+impl Display for HttpScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HttpScheme::Http => "http",
+            HttpScheme::Https => "https",
+        })
+    }
+}
+
+/// Where and when a request came in from, on its request entity and alongside it in handler mailboxes.
+#[derive(Component, Debug, Clone)]
+pub struct HttpRequestInfo {
+    /// A unique ID for the request, which is echoed back in the `X-Request-Id` response header. Requests from a
+    /// trusted proxy keep the `X-Request-Id` they came in with, so they can be followed from one server to the next.
+    pub id: String,
+    /// The address of the peer that sent the request. This is a proxy's if it came through one, see
+    /// [`super::proxy::HttpClientAddr`] for the client behind it.
+    pub peer_addr: HttpAddr,
+    /// The address of the listener the request came in on.
+    pub local_addr: HttpAddr,
+    /// The same listener, as written in [`crate::config::ServiceConfig::bind_addresses`] (or
+    /// [`crate::config::MetricsConfig::listeners`]).
+    pub listener: String,
+    pub scheme: HttpScheme,
+    /// When the connection the request came in on was accepted.
+    pub accepted_at: DateTime<Local>,
+    /// When the request was received in full.
+    pub received_at: DateTime<Local>,
+}
+
+impl HttpRequestInfo {
+    pub(in crate::http) fn new(
+        id: String,
+        peer_addr: HttpAddr,
+        local_addr: HttpAddr,
+        listener: String,
+        scheme: HttpScheme,
+        accepted_at: DateTime<Local>,
+    ) -> Self {
+        Self {
+            id,
+            peer_addr,
+            local_addr,
+            listener,
+            scheme,
+            accepted_at,
+            received_at: Local::now(),
+        }
+    }
+}
+
+/// The header a request's ID is taken from, when it comes from a trusted proxy, and echoed back in.
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The longest incoming request ID that's taken as is. Anything longer gets a fresh one instead.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// A request's ID, in the extensions of the request handlers see, so it can be logged and shown in error pages.
+/// The same as [`HttpRequestInfo::id`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequestId(pub String);
+
+impl HttpRequestId {
+    /// Gets the ID of a request, as handed to handlers.
+    pub fn of<B>(request: &Request<B>) -> Option<&str> {
+        request.extensions().get::<HttpRequestId>().map(|id| id.0.as_str())
+    }
+}
+
+/// Takes the request ID a trusted proxy gave a request, or makes up a new one. Incoming IDs end up in log lines, so
+/// ones that aren't short and printable are thrown out.
+pub(in crate::http) fn request_id(headers: &HeaderMap, peer: &HttpAddr, trusted: &[TrustedProxy]) -> String {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .filter(|_| is_trusted(peer, trusted))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string)
+}
+
+/// Echoes a request's ID back in its response, unless the handler already set one.
+pub(in crate::http) fn tag_reply(reply: &mut HttpReplyResult, id: &str) {
+    if let Ok(response) = reply {
+        if let Ok(value) = HeaderValue::from_str(id) {
+            response.headers_mut().entry(X_REQUEST_ID).or_insert(value);
+        }
+    }
+}
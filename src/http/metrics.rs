@@ -0,0 +1,215 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use bevy::prelude::*;
+use http::{
+    header::{ALLOW, CONTENT_TYPE},
+    HeaderValue, Method, Response, StatusCode,
+};
+use hyper::Body;
+
+use super::{
+    error::HttpHandlerError, events::HttpRequestReplyEvent, middleware::HttpMiddlewareQueue, overload::HttpLoadStats,
+    service_adapter::HttpReplyResult,
+};
+use crate::config::ServiceConfig;
+
+/// The content type of the Prometheus text exposition format.
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// What requests that weren't handed to a route are counted under.
+const UNROUTED: &str = "unrouted";
+
+/// The route pattern a request is counted under in the metrics. Routers should attach it once they've picked where a
+/// request goes, otherwise it's counted as unrouted. Keep it to patterns rather than paths, or every distinct path
+/// gets its own series.
+#[derive(Component, Debug, Clone)]
+pub struct HttpMetricsRoute(pub String);
+
+/// A latency histogram, with a count for each bucket rather than a running total.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, bounds: &[f64], value: f64) {
+        self.buckets.resize(bounds.len(), 0);
+        if let Some(i) = bounds.iter().position(|&b| value <= b) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// What the server's been up to, served in the Prometheus text format on [`crate::config::MetricsConfig::path`].
+/// Connection and queue gauges come from [`HttpLoadStats`].
+#[derive(Resource, Debug, Default)]
+pub struct HttpMetrics {
+    /// Requests finished, by route, method and status.
+    requests: BTreeMap<(String, &'static str, String), u64>,
+    /// Time from a request starting to it being replied to, by route and method. A connection's first request starts
+    /// when the connection's accepted, and later ones when hyper starts reading them.
+    latency: BTreeMap<(String, &'static str), Histogram>,
+    /// Requests waiting in handlers' mailboxes at the end of the last frame, by route. Handlers sharing a route are
+    /// summed, so each route is one series.
+    mailbox_depths: BTreeMap<String, usize>,
+    asset_load_failures: u64,
+}
+
+impl HttpMetrics {
+    /// Counts a finished request. `status` and `latency` are `None` if it was never replied to.
+    pub(in crate::http) fn record_request(
+        &mut self,
+        route: Option<&str>,
+        method: &Method,
+        status: Option<StatusCode>,
+        latency: Option<Duration>,
+        buckets: &[f64],
+    ) {
+        let route = route.unwrap_or(UNROUTED).to_string();
+        let method = method_label(method);
+        let status = status.map_or_else(|| "none".to_string(), |s| s.as_u16().to_string());
+
+        *self.requests.entry((route.clone(), method, status)).or_default() += 1;
+        if let Some(latency) = latency {
+            self.latency.entry((route, method)).or_default().observe(buckets, latency.as_secs_f64());
+        }
+    }
+
+    /// Replaces the mailbox depths with the ones given, as `(route, depth)`. Depths given for the same route are added
+    /// up.
+    pub fn set_mailbox_depths(&mut self, depths: impl IntoIterator<Item = (String, usize)>) {
+        self.mailbox_depths.clear();
+        for (route, depth) in depths {
+            *self.mailbox_depths.entry(route).or_default() += depth;
+        }
+    }
+
+    /// Counts an asset that failed to load.
+    pub fn record_asset_load_failure(&mut self) {
+        self.asset_load_failures += 1;
+    }
+
+    /// Writes everything out in the Prometheus text format.
+    pub fn render(&self, stats: &HttpLoadStats, buckets: &[f64]) -> String {
+        let mut out = String::new();
+
+        family(&mut out, "bevyblog_requests_total", "counter", "Requests finished, by route, method and status.");
+        for ((route, method, status), count) in &self.requests {
+            let route = escape(route);
+            let _ = writeln!(out, "bevyblog_requests_total{{route=\"{route}\",method=\"{method}\",status=\"{status}\"}} {count}");
+        }
+
+        family(
+            &mut out,
+            "bevyblog_request_duration_seconds",
+            "histogram",
+            "Time from a request starting (or its connection being accepted, if it's the first) to the reply, by route and method.",
+        );
+        for ((route, method), histogram) in &self.latency {
+            let labels = format!("route=\"{}\",method=\"{method}\"", escape(route));
+            let mut cumulative = 0;
+            for (i, bound) in buckets.iter().enumerate() {
+                cumulative += histogram.buckets.get(i).copied().unwrap_or_default();
+                let _ = writeln!(out, "bevyblog_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "bevyblog_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "bevyblog_request_duration_seconds_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "bevyblog_request_duration_seconds_count{{{labels}}} {}", histogram.count);
+        }
+
+        family(&mut out, "bevyblog_mailbox_depth", "gauge", "Requests waiting in a route's handler mailboxes at the end of the last frame.");
+        for (route, depth) in &self.mailbox_depths {
+            let _ = writeln!(out, "bevyblog_mailbox_depth{{route=\"{}\"}} {depth}", escape(route));
+        }
+
+        let scalars = [
+            ("bevyblog_open_connections", "gauge", "Connections currently open, WebSockets included.", stats.open_connections as u64),
+            ("bevyblog_queued_requests", "gauge", "Requests received that haven't been replied to yet.", stats.queued_requests as u64),
+            ("bevyblog_connections_accepted_total", "counter", "Connections accepted.", stats.connections_accepted),
+            ("bevyblog_connections_rejected_total", "counter", "Connections turned away for being over the limit.", stats.connections_rejected),
+            ("bevyblog_requests_received_total", "counter", "Requests received.", stats.requests_received),
+            ("bevyblog_requests_rejected_total", "counter", "Requests turned away for being over the queue limit.", stats.requests_rejected),
+            ("bevyblog_asset_load_failures_total", "counter", "Assets that failed to load.", self.asset_load_failures),
+        ];
+        for (name, kind, help, value) in scalars {
+            family(&mut out, name, kind, help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        out
+    }
+}
+
+/// Writes the HELP and TYPE lines that start a metric family.
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+/// Methods outside the standard ones are lumped together, so clients can't make up new series at will.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => "OTHER",
+    }
+}
+
+/// Escapes a label value for the text format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// A request filter serving the metrics on [`crate::config::MetricsConfig::path`]. Requests on a metrics listener
+/// never make it past here.
+pub(in crate::http) fn http_metrics_system(
+    cfg: Res<ServiceConfig>,
+    metrics: Res<HttpMetrics>,
+    stats: Res<HttpLoadStats>,
+    mut queue: ResMut<HttpMiddlewareQueue>,
+    mut replies: EventWriter<HttpRequestReplyEvent>,
+    mut commands: Commands,
+) {
+    let Some(metrics_cfg) = &cfg.metrics else {
+        return;
+    };
+
+    for req in queue.pending() {
+        let on_metrics_listener = metrics_cfg.listeners.contains(&req.info.listener);
+        let is_metrics_path = req.request.uri().path() == metrics_cfg.path;
+        let served_here = on_metrics_listener || (metrics_cfg.listeners.is_empty() && is_metrics_path);
+
+        if !served_here {
+            continue;
+        }
+
+        if !is_metrics_path {
+            req.reply(&mut replies, Err(Box::new(HttpHandlerError::new(StatusCode::NOT_FOUND))));
+            continue;
+        }
+
+        commands.entity(req.ent).insert(HttpMetricsRoute(metrics_cfg.path.clone()));
+        let result: HttpReplyResult = match *req.request.method() {
+            Method::GET | Method::HEAD => {
+                let mut response = Response::new(Body::from(metrics.render(&stats, &metrics_cfg.latency_buckets)));
+                response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_PROMETHEUS));
+                Ok(response)
+            }
+            _ => {
+                let error = HttpHandlerError::new(StatusCode::METHOD_NOT_ALLOWED)
+                    .with_header(ALLOW, HeaderValue::from_static("GET, HEAD"));
+                Err(Box::new(error))
+            }
+        };
+        req.reply(&mut replies, result);
+    }
+}
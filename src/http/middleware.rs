@@ -0,0 +1,261 @@
+use std::sync::{Arc, RwLock};
+
+use bevy::prelude::*;
+use http::{header::HeaderName, HeaderValue, Method, Request, Response, Uri};
+use hyper::{
+    body::{Bytes, Sender},
+    Body,
+};
+use log::warn;
+
+use super::{
+    events::{HttpRequestReceivedEvent, HttpRequestReplyEvent},
+    info::HttpRequestInfo,
+    service_adapter::HttpReplyResult,
+    socket::HttpAddr,
+    HttpRequestStages,
+};
+use crate::config::{HeadersConfig, ServiceConfig};
+
+/// A request on its way through the [`HttpRequestStages::Middleware`] stage, which filters can rewrite or reply to.
+/// Whatever's left once every filter has run goes out as a [`HttpRequestReceivedEvent`].
+pub struct HttpPendingRequest {
+    pub ent: Entity,
+    pub request: Request<Bytes>,
+    pub peer_addr: HttpAddr,
+    pub client_addr: HttpAddr,
+    pub info: HttpRequestInfo,
+    stopped: bool,
+}
+
+impl HttpPendingRequest {
+    /// Replies to the request then and there, so it goes no further.
+    pub fn reply(&mut self, replies: &mut EventWriter<HttpRequestReplyEvent>, result: HttpReplyResult) {
+        replies.send(HttpRequestReplyEvent::new(result, self.ent));
+        self.stop();
+    }
+
+    /// Stops the request going any further, for filters that reply to it some other way.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+}
+
+/// The requests received this frame, held back while the request filters run over them.
+#[derive(Resource, Default)]
+pub struct HttpMiddlewareQueue {
+    requests: Vec<HttpPendingRequest>,
+}
+
+impl HttpMiddlewareQueue {
+    pub(in crate::http) fn push(
+        &mut self,
+        ent: Entity,
+        request: Request<Bytes>,
+        peer_addr: HttpAddr,
+        client_addr: HttpAddr,
+        info: HttpRequestInfo,
+    ) {
+        self.requests.push(HttpPendingRequest {
+            ent,
+            request,
+            peer_addr,
+            client_addr,
+            info,
+            stopped: false,
+        });
+    }
+
+    /// The requests no filter has stopped yet, in the order they came in.
+    pub fn pending(&mut self) -> impl Iterator<Item = &mut HttpPendingRequest> {
+        self.requests.iter_mut().filter(|r| !r.stopped)
+    }
+}
+
+/// A reply on its way through the [`HttpRequestStages::ResponseFilter`] stage, which filters can add headers to or
+/// otherwise rewrite before it's sent.
+pub struct HttpPendingReply {
+    pub ent: Entity,
+    /// The method of the request being replied to, as it came in.
+    pub method: Method,
+    /// The URI of the request being replied to, as it came in.
+    pub uri: Uri,
+    /// The reply. Streamed and WebSocket replies already have their body hooked up, so only touch their head.
+    pub result: HttpReplyResult,
+    pub(in crate::http) stream: Option<Sender>,
+    pub(in crate::http) websocket: bool,
+}
+
+/// The replies sent since last frame, held back while the response filters run over them.
+#[derive(Resource, Default)]
+pub struct HttpReplyQueue {
+    pub(in crate::http) replies: Vec<HttpPendingReply>,
+}
+
+impl HttpReplyQueue {
+    /// The replies waiting to be sent, in the order they were made.
+    pub fn pending(&mut self) -> impl Iterator<Item = &mut HttpPendingReply> {
+        self.replies.iter_mut()
+    }
+}
+
+/// The systems that bookend the filter stages, which every filter runs between.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemLabel)]
+pub(in crate::http) enum HttpFilterSystems {
+    /// Raises whatever made it through the request filters as events.
+    Dispatch,
+    /// Gathers up the replies for the response filters.
+    Collect,
+    /// Applies [`ServiceConfig::headers`], once every other response filter is done.
+    Headers,
+}
+
+/// Labels a filter by its place in the chain, so each can run after the one added before it.
+struct HttpFilterLabel(&'static str);
+
+impl SystemLabel for HttpFilterLabel {
+    fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+/// The filters added so far, in order.
+#[derive(Resource, Default)]
+struct HttpFilterChains {
+    requests: Vec<&'static str>,
+    responses: Vec<&'static str>,
+}
+
+/// Labels the next filter in a chain, returning its label and the label of the filter before it.
+fn next_label(chain: &mut Vec<&'static str>, kind: &str) -> (HttpFilterLabel, Option<HttpFilterLabel>) {
+    let previous = chain.last().copied().map(HttpFilterLabel);
+    // Labels have to be 'static, and there's one per filter for the life of the app.
+    let label: &'static str = Box::leak(format!("Http{kind}Filter{}", chain.len()).into_boxed_str());
+    chain.push(label);
+    (HttpFilterLabel(label), previous)
+}
+
+/// Adds request and response filters to an app with the [`super::HttpRequestPlugin`].
+pub trait HttpFilterAppExt {
+    /// Adds a system to the [`HttpRequestStages::Middleware`] stage, running after every request filter added before
+    /// it and before the requests go on to be routed. Filters work through [`HttpMiddlewareQueue::pending`], and can
+    /// rewrite requests, reply to them to stop them going any further, or attach components to their entities.
+    fn add_request_filter<Params>(&mut self, filter: impl IntoSystemDescriptor<Params>) -> &mut Self;
+
+    /// Adds a system to the [`HttpRequestStages::ResponseFilter`] stage, running after every response filter added
+    /// before it and before the replies are sent. Filters work through [`HttpReplyQueue::pending`].
+    fn add_response_filter<Params>(&mut self, filter: impl IntoSystemDescriptor<Params>) -> &mut Self;
+}
+
+impl HttpFilterAppExt for App {
+    fn add_request_filter<Params>(&mut self, filter: impl IntoSystemDescriptor<Params>) -> &mut Self {
+        let mut chains = self.world.get_resource_or_insert_with(HttpFilterChains::default);
+        let (label, previous) = next_label(&mut chains.requests, "Request");
+
+        let mut filter = filter.label(label).before(HttpFilterSystems::Dispatch);
+        if let Some(previous) = previous {
+            filter = filter.after(previous);
+        }
+
+        self.add_system_to_stage(HttpRequestStages::Middleware, filter)
+    }
+
+    fn add_response_filter<Params>(&mut self, filter: impl IntoSystemDescriptor<Params>) -> &mut Self {
+        let mut chains = self.world.get_resource_or_insert_with(HttpFilterChains::default);
+        let (label, previous) = next_label(&mut chains.responses, "Response");
+
+        let mut filter = filter
+            .label(label)
+            .after(HttpFilterSystems::Collect)
+            .before(HttpFilterSystems::Headers);
+        if let Some(previous) = previous {
+            filter = filter.after(previous);
+        }
+
+        self.add_system_to_stage(HttpRequestStages::ResponseFilter, filter)
+    }
+}
+
+/// Sends whatever made it through the filters on to be routed.
+pub(in crate::http) fn http_middleware_dispatch_system(
+    mut queue: ResMut<HttpMiddlewareQueue>,
+    mut recv_ev_writer: EventWriter<HttpRequestReceivedEvent>,
+) {
+    for request in queue.requests.drain(..).filter(|r| !r.stopped) {
+        recv_ev_writer.send(HttpRequestReceivedEvent {
+            body: Arc::new(request.request),
+            ent: request.ent,
+            peer_addr: request.peer_addr,
+            client_addr: request.client_addr,
+            info: request.info,
+        });
+    }
+}
+
+/// [`HeadersConfig`], parsed.
+#[derive(Default)]
+pub(in crate::http) struct ParsedHeaders {
+    add: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl ParsedHeaders {
+    fn parse(cfg: &HeadersConfig) -> Self {
+        let name = |name: &str| match HeaderName::try_from(name) {
+            Ok(name) => Some(name),
+            Err(_) => {
+                warn!("Ignoring the response header {name:?} in the config, it isn't a valid header name.");
+                None
+            }
+        };
+
+        Self {
+            add: cfg
+                .add
+                .iter()
+                .filter_map(|(n, v)| match HeaderValue::try_from(v) {
+                    Ok(value) => name(n).map(|name| (name, value)),
+                    Err(_) => {
+                        warn!("Ignoring the response header {n:?} in the config, {v:?} isn't a valid value.");
+                        None
+                    }
+                })
+                .collect(),
+            remove: cfg.remove.iter().filter_map(|n| name(n)).collect(),
+        }
+    }
+}
+
+/// [`ServiceConfig::headers`], parsed and shared with every connection. Most replies have them applied as the last
+/// response filter, but they're also applied to requests turned away before they get an entity (for a body that's too
+/// large, or the queue being full), and by connections to the 500 for a request dropped without a reply.
+#[derive(Resource, Clone, Default)]
+pub(in crate::http) struct HttpResponseHeaders(Arc<RwLock<ParsedHeaders>>);
+
+impl HttpResponseHeaders {
+    pub(in crate::http) fn apply(&self, response: &mut Response<Body>) {
+        let headers = self.0.read().unwrap();
+        for name in &headers.remove {
+            response.headers_mut().remove(name);
+        }
+        for (name, value) in &headers.add {
+            response.headers_mut().entry(name).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+/// Parses [`ServiceConfig::headers`] whenever the config changes, ahead of any connections being accepted.
+pub(in crate::http) fn http_response_headers_config_system(cfg: Res<ServiceConfig>, headers: Res<HttpResponseHeaders>) {
+    if cfg.is_changed() {
+        *headers.0.write().unwrap() = ParsedHeaders::parse(&cfg.headers);
+    }
+}
+
+/// Applies [`ServiceConfig::headers`] to every reply, as the last response filter.
+pub(in crate::http) fn http_response_headers_system(headers: Res<HttpResponseHeaders>, mut queue: ResMut<HttpReplyQueue>) {
+    for reply in queue.pending() {
+        if let Ok(response) = &mut reply.result {
+            headers.apply(response);
+        }
+    }
+}
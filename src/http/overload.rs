@@ -0,0 +1,49 @@
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+};
+
+use bevy::prelude::*;
+use http::{header::RETRY_AFTER, HeaderValue, StatusCode};
+
+use super::{error::HttpHandlerError, socket::AcceptedSocket};
+
+/// Counters for how busy the server is and how much it's had to turn away, for tuning
+/// [`crate::config::LimitsConfig`]. The gauges are updated once a frame.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct HttpLoadStats {
+    /// Connections currently open, upgraded ones included.
+    pub open_connections: usize,
+    /// Requests received that haven't been replied to yet.
+    pub queued_requests: usize,
+    /// Connections accepted since startup.
+    pub connections_accepted: u64,
+    /// Connections turned away for being over [`crate::config::LimitsConfig::max_connections`].
+    pub connections_rejected: u64,
+    /// Requests received since startup.
+    pub requests_received: u64,
+    /// Requests turned away for being over [`crate::config::LimitsConfig::max_queued_requests`].
+    pub requests_rejected: u64,
+}
+
+/// The error for a request that can't be taken on right now.
+pub(in crate::http) fn service_unavailable(retry_after_secs: u64) -> HttpHandlerError {
+    HttpHandlerError::new(StatusCode::SERVICE_UNAVAILABLE).with_header(RETRY_AFTER, HeaderValue::from(retry_after_secs))
+}
+
+/// Turns a plain HTTP connection away without spinning anything up for it, by writing a canned 503 straight to the
+/// socket and closing it. This is best effort, a client that hasn't sent its request yet may see a reset instead.
+pub(in crate::http) fn reject_connection(mut stream: AcceptedSocket, retry_after_secs: u64) {
+    let body = "503 Service Unavailable.";
+    let response = format!(
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {retry_after_secs}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    // Whatever the client already sent has to be read, or closing the socket resets the connection, which can take
+    // our response down with it.
+    let _ = stream.set_nonblocking(true);
+    let _ = stream.read(&mut [0; 4096]);
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.shutdown(Shutdown::Write);
+}
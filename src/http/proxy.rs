@@ -0,0 +1,376 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use bevy::prelude::*;
+use http::{header::FORWARDED, HeaderMap};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::socket::HttpAddr;
+use crate::config::{ForwardedHeader, ProxyConfig, TrustedProxy};
+
+/// The address of the client behind a request, seen through any trusted proxies in the way.
+/// Use this rather than the connection's address for logging, rate limiting and access control.
+#[derive(Component, Debug, Clone, PartialEq, Eq)]
+pub struct HttpClientAddr(pub HttpAddr);
+
+/// The client address a PROXY protocol header gave for a connection, attached to the extensions of each of its
+/// requests.
+#[derive(Debug, Clone, Copy)]
+pub(in crate::http) struct ProxyProtocolPeer(pub SocketAddr);
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a v1 header may be, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the PROXY protocol header a connection starts with, returning the client address it gives.
+/// Connections the proxy makes on its own behalf (health checks and the like) don't carry one, and give None.
+pub(in crate::http) async fn read_proxy_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both versions are at least this long, so this can't read past the header.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("no PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Whatever follows the CRLF belongs to hyper, so the rest is read a byte at a time to not eat into it.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY v1 header isn't text"))?;
+    match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad source address in PROXY v1 header"))?;
+            let port: u16 = source_port.parse().map_err(|_| invalid("bad source port in PROXY v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let [version_command, family, len_high, len_low] = head;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let mut addresses = vec![0u8; usize::from(u16::from_be_bytes([len_high, len_low]))];
+    stream.read_exact(&mut addresses).await?;
+
+    // A LOCAL connection, made by the proxy itself.
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+
+    // The source address comes first, then the destination address, then the source and destination ports.
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // Unspecified, or a unix socket on the proxy's side, neither of which tell us anything useful.
+        0 | 3 => Ok(None),
+        _ => Err(invalid("malformed PROXY v2 header")),
+    }
+}
+
+pub(in crate::http) fn is_trusted(addr: &HttpAddr, trusted: &[TrustedProxy]) -> bool {
+    trusted.iter().any(|t| match (t, addr) {
+        (TrustedProxy::Unix, HttpAddr::Unix(_)) => true,
+        (TrustedProxy::Cidr(cidr), HttpAddr::Tcp(addr)) => {
+            // IPv4 clients of a dual stack listener show up as IPv4 mapped IPv6 addresses.
+            let ip = match addr.ip() {
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+                ip => ip,
+            };
+            cidr.contains(ip)
+        }
+        _ => false,
+    })
+}
+
+/// Parses a single hop from a forwarding header, which may come with a port, quotes and brackets.
+fn parse_hop(hop: &str) -> Option<SocketAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+
+    let ip = hop.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(hop);
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+/// The hops a request went through according to the forwarding header the trusted proxies write, client first.
+fn forwarded_hops(headers: &HeaderMap, header: ForwardedHeader) -> Vec<&str> {
+    let values = |name| headers.get_all(name).into_iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(','));
+
+    match header {
+        ForwardedHeader::XForwardedFor => values("x-forwarded-for").collect(),
+        ForwardedHeader::Forwarded => values(FORWARDED.as_str())
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .map_or("", |(_, value)| value)
+            })
+            .collect(),
+    }
+}
+
+/// Works out who a request is really from. Starting at the peer, this walks back through the forwarding headers
+/// for as long as each hop is a trusted proxy, so a client can't pass itself off as someone else.
+pub(in crate::http) fn resolve_client_addr(peer: &HttpAddr, headers: &HeaderMap, cfg: &ProxyConfig) -> HttpAddr {
+    let trusted = &cfg.trusted_proxies;
+    let mut client = peer.clone();
+    if !is_trusted(&client, trusted) {
+        return client;
+    }
+
+    for hop in forwarded_hops(headers, cfg.forwarded_header).into_iter().rev() {
+        // Either "unknown", or an obfuscated identifier, so there's no seeing any further back.
+        let Some(addr) = parse_hop(hop) else {
+            break;
+        };
+
+        client = HttpAddr::Tcp(addr);
+        if !is_trusted(&client, trusted) {
+            break;
+        }
+    }
+
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use http::HeaderValue;
+    use tokio::runtime::Builder;
+
+    use super::*;
+    use crate::config::IpCidr;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    /// Reads a header off `bytes`, giving what it says and whatever it left unread.
+    fn read(bytes: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = bytes;
+        let result = block_on(read_proxy_header(&mut stream));
+        (result, stream.to_vec())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn proxies(trusted: &[&str], forwarded_header: ForwardedHeader) -> ProxyConfig {
+        ProxyConfig {
+            trusted_proxies: trusted.iter().map(|t| TrustedProxy::try_from(t.to_string()).unwrap()).collect(),
+            forwarded_header,
+            ..Default::default()
+        }
+    }
+
+    fn header_map(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn resolve(peer: &str, headers: &HeaderMap, cfg: &ProxyConfig) -> HttpAddr {
+        resolve_client_addr(&HttpAddr::Tcp(addr(peer)), headers, cfg)
+    }
+
+    #[test]
+    fn reads_v1_headers() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 51234 443\r\nGET / HTTP/1.1\r\n");
+        assert_eq!(result.unwrap(), Some(addr("192.0.2.1:51234")));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n");
+        assert_eq!(result.unwrap(), Some(addr("[2001:db8::1]:51234")));
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nGET");
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[test]
+    fn reads_v2_headers() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xC8, 0x22, 0x01, 0xBB];
+        let mut bytes = v2(1, 0x11, &addresses);
+        bytes.extend(b"GET");
+        let (result, rest) = read(&bytes);
+        assert_eq!(result.unwrap(), Some(addr("192.0.2.1:51234")));
+        assert_eq!(rest, b"GET");
+
+        let mut addresses = [0u8; 36];
+        addresses[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses[32..34].copy_from_slice(&51234u16.to_be_bytes());
+        let (result, _) = read(&v2(1, 0x21, &addresses));
+        assert_eq!(result.unwrap(), Some(addr("[2001:db8::1]:51234")));
+
+        // LOCAL connections skip over whatever addresses they carry.
+        let (result, rest) = read(&[v2(0, 0x11, &[0; 12]), b"GET".to_vec()].concat());
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert!(read(b"PROXY TC").0.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 51234 443").0.is_err());
+        assert!(read(&V2_SIGNATURE).0.is_err());
+        assert!(read(&v2(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xC8, 0x22, 0x01, 0xBB])[..20]).0.is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").0.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 51234\r\n").0.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.300 198.51.100.1 51234 443\r\n").0.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n").0.is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 200], b"\r\n"].concat()).0.is_err());
+
+        // The wrong version, and an address block too short for its family.
+        let mut bytes = v2(1, 0x11, &[0; 12]);
+        bytes[12] = 0x11;
+        assert!(read(&bytes).0.is_err());
+        assert!(read(&v2(1, 0x11, &[0; 8])).0.is_err());
+    }
+
+    #[test]
+    fn parses_hops() {
+        assert_eq!(parse_hop("192.0.2.1"), Some(addr("192.0.2.1:0")));
+        assert_eq!(parse_hop(" 192.0.2.1:8080"), Some(addr("192.0.2.1:8080")));
+        assert_eq!(parse_hop("\"[2001:db8::1]:8080\""), Some(addr("[2001:db8::1]:8080")));
+        assert_eq!(parse_hop("\"[2001:db8::1]\""), Some(addr("[2001:db8::1]:0")));
+        assert_eq!(parse_hop("2001:db8::1"), Some(addr("[2001:db8::1]:0")));
+        assert_eq!(parse_hop("unknown"), None);
+        assert_eq!(parse_hop("_hidden"), None);
+        assert_eq!(parse_hop(""), None);
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        let cidr: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.255.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let single: IpCidr = "192.0.2.1".parse().unwrap();
+        assert!(single.contains("192.0.2.1".parse().unwrap()));
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        let everything: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("203.0.113.9".parse().unwrap()));
+
+        let v6: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        assert!(!v6.contains("10.0.0.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("2001:db8::/129".parse::<IpCidr>().is_err());
+        assert!("10.0.0.0/".parse::<IpCidr>().is_err());
+        assert!("example.com/8".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let cfg = proxies(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let headers = header_map(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(resolve("203.0.113.9:1234", &headers, &cfg), HttpAddr::Tcp(addr("203.0.113.9:1234")));
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        let cfg = proxies(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        // The client made up the first entry, then went through two of our proxies.
+        let headers = header_map(&[("x-forwarded-for", "127.0.0.1, 192.0.2.1, 10.0.0.2")]);
+        assert_eq!(resolve("10.0.0.1:1234", &headers, &cfg), HttpAddr::Tcp(addr("192.0.2.1:0")));
+
+        // Entries can be split over several headers.
+        let headers = header_map(&[("x-forwarded-for", "192.0.2.1"), ("x-forwarded-for", "10.0.0.2")]);
+        assert_eq!(resolve("10.0.0.1:1234", &headers, &cfg), HttpAddr::Tcp(addr("192.0.2.1:0")));
+
+        // With every hop trusted, the first one is as far back as it goes.
+        let headers = header_map(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(resolve("10.0.0.1:1234", &headers, &cfg), HttpAddr::Tcp(addr("10.0.0.3:0")));
+
+        let headers = header_map(&[("x-forwarded-for", "unknown, 10.0.0.2")]);
+        assert_eq!(resolve("10.0.0.1:1234", &headers, &cfg), HttpAddr::Tcp(addr("10.0.0.2:0")));
+    }
+
+    #[test]
+    fn only_reads_the_configured_header() {
+        // Our proxy appends to X-Forwarded-For and passes on a Forwarded the client made up.
+        let headers = header_map(&[("forwarded", "for=127.0.0.1"), ("x-forwarded-for", "192.0.2.1")]);
+        let cfg = proxies(&["10.0.0.0/8", "127.0.0.1"], ForwardedHeader::XForwardedFor);
+        assert_eq!(resolve("10.0.0.1:1234", &headers, &cfg), HttpAddr::Tcp(addr("192.0.2.1:0")));
+
+        // And the other way around.
+        let headers = header_map(&[("forwarded", "for=\"[2001:db8::1]:4711\";proto=https"), ("x-forwarded-for", "127.0.0.1")]);
+        let cfg = proxies(&["10.0.0.0/8", "127.0.0.1"], ForwardedHeader::Forwarded);
+        assert_eq!(resolve("10.0.0.1:1234", &headers, &cfg), HttpAddr::Tcp(addr("[2001:db8::1]:4711")));
+    }
+
+    #[test]
+    fn trusts_ipv4_mapped_peers() {
+        let cfg = proxies(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let headers = header_map(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(resolve("[::ffff:10.0.0.1]:1234", &headers, &cfg), HttpAddr::Tcp(addr("192.0.2.1:0")));
+        assert_eq!(resolve("[::ffff:11.0.0.1]:1234", &headers, &cfg), HttpAddr::Tcp(addr("[::ffff:11.0.0.1]:1234")));
+    }
+}
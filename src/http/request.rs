@@ -1,96 +1,379 @@
 use bevy::{
+    app::AppExit,
     prelude::*,
-    tasks::{IoTaskPool, Task, ComputeTaskPool}, app::AppExit,
+    tasks::{ComputeTaskPool, Task},
 };
-use http::{Request, Response};
-use hyper::{server::conn::Http, Body};
+use chrono::{DateTime, Local};
+use hyper::{server::conn::Http, upgrade::OnUpgrade};
 use log::{error, info};
-use std::{error::Error, net::TcpListener, sync::Mutex};
-use std::{io::ErrorKind, sync::mpsc};
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    sync::Mutex,
+    task::Poll,
+    time::Instant,
+};
+use std::io::ErrorKind;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{oneshot, watch},
+};
+
+use crate::{
+    config::{BindMode, ServiceConfig},
+    http::service_adapter::{request_channel, BevyExecutor, HttpConnectionServicer, HttpReplyResult, HttpRequestReceiver},
+    reactor::IoReactor,
+};
 
-use crate::{custtcpstream, http::service_adapter::HttpSingleServicer};
+use super::{
+    access_log::HttpAccessRecord,
+    deadline::HttpRequestDeadline,
+    error::HttpErrorRenderer,
+    middleware::HttpResponseHeaders,
+    info::{HttpRequestInfo, HttpScheme},
+    overload::{reject_connection, HttpLoadStats},
+    proxy::{read_proxy_header, HttpClientAddr},
+    shutdown::{wait_for_shutdown, HttpShutdownState},
+    socket::{HttpAddr, ListenerSocket},
+    tls::TlsContext,
+    websocket::HttpWebSocket,
+};
 
+/// A single client connection, which may carry many requests over its lifetime.
 #[derive(Component)]
-pub(in crate::http) struct HttpRequestComponent {
+pub(in crate::http) struct HttpConnectionComponent {
     pub task: Task<()>,
-    pub rxreq: Mutex<mpsc::Receiver<Request<Body>>>,
-    pub txres: mpsc::SyncSender<Result<Response<Body>, Box<dyn Error + Send + Sync>>>,
+    pub rxreq: Mutex<HttpRequestReceiver>,
+    pub peer_addr: HttpAddr,
+    pub local_addr: HttpAddr,
+    /// The bind address the connection came in on, as written in the config.
+    pub listener: String,
+    pub scheme: HttpScheme,
+    pub accepted_at: DateTime<Local>,
+    /// How many requests this connection has carried so far.
+    pub served: usize,
+    /// Requests received on this connection that haven't been replied to yet.
+    pub in_flight: usize,
+    /// When a request was last received or replied to, used for the idle timeout.
+    pub last_active: Instant,
 }
 
 #[derive(Bundle)]
-struct HttpRequestEntityBundle {
-    request: HttpRequestComponent,
+struct HttpConnectionEntityBundle {
+    connection: HttpConnectionComponent,
     name: Name,
 }
 
+/// A single request received on a connection. The entity is despawned once it's been replied to.
+#[derive(Component)]
+pub(in crate::http) struct HttpRequestComponent {
+    pub connection: Entity,
+    /// Where the reply goes. Taken when the reply is sent.
+    pub txres: Option<oneshot::Sender<HttpReplyResult>>,
+    /// Written to the access log once the request is finalized.
+    pub record: HttpAccessRecord,
+    /// Resolves to the connection once it's been upgraded, for requests that asked for an upgrade.
+    pub upgrade: Option<OnUpgrade>,
+}
+
+#[derive(Bundle)]
+pub(in crate::http) struct HttpRequestEntityBundle {
+    pub request: HttpRequestComponent,
+    pub deadline: HttpRequestDeadline,
+    pub client: HttpClientAddr,
+    pub info: HttpRequestInfo,
+    pub name: Name,
+}
+
+/// A bound listener, alongside the address it ended up bound to.
+pub(in crate::http) struct HttpListener {
+    listener: ListenerSocket,
+    local_addr: HttpAddr,
+    /// The address as written in the config.
+    address: String,
+    /// Whether connections on this listener start with a TLS handshake.
+    tls: bool,
+    /// Whether connections on this listener start with a PROXY protocol header, ahead of any TLS handshake.
+    proxy_protocol: bool,
+}
+
 #[derive(Resource, Default)]
 pub(in crate::http) struct HttpRequestContext {
-    listener: Option<TcpListener>,
+    /// One listener per bound address, in the order they appear in [`ServiceConfig::bind_addresses`], followed by the
+    /// metrics listeners.
+    listeners: Vec<HttpListener>,
+    /// Set when connections are made by hand rather than accepted, so nothing gets bound.
+    detached: bool,
 }
 
-pub(in crate::http) fn http_request_listener_system(mut ctx: ResMut<HttpRequestContext>, mut commands: Commands, mut exit: EventWriter<AppExit>) {
-    if let None = ctx.listener {
-        match TcpListener::bind("0.0.0.0:8080") {
-            Ok(l) => ctx.listener = Some(l),
+impl HttpRequestContext {
+    /// Stops the listener system binding anything, for when connections are spawned by hand.
+    #[cfg(any(test, feature = "testing"))]
+    pub(in crate::http) fn detach(&mut self) {
+        self.detached = true;
+    }
+}
+
+/// Binds a single address from the config, setting it up for use by the listener system.
+fn bind_listener(address: &str, cfg: &ServiceConfig) -> std::io::Result<HttpListener> {
+    let (listener, local_addr) = ListenerSocket::bind(address, cfg.unix_socket_mode)?;
+    let tls = cfg.tls.as_ref().is_some_and(|t| t.listeners.iter().any(|l| l == address));
+    let proxy_protocol = cfg.proxy.proxy_protocol_listeners.iter().any(|l| l == address);
+
+    Ok(HttpListener {
+        listener,
+        local_addr,
+        address: address.to_string(),
+        tls,
+        proxy_protocol,
+    })
+}
+
+/// Binds the configured addresses according to the configured [`BindMode`].
+/// Returns None if the configuration can't be satisfied, having already logged why.
+fn bind_listeners(cfg: &ServiceConfig) -> Option<Vec<HttpListener>> {
+    let mut listeners = Vec::new();
+
+    for address in &cfg.bind_addresses {
+        match bind_listener(address, cfg) {
+            Ok(l) => {
+                let scheme = if l.tls { "https" } else { "http" };
+                info!("Listening for {scheme} on {} (configured as \"{address}\").", l.local_addr);
+                listeners.push(l);
+
+                if cfg.bind_mode == BindMode::FirstAvailable {
+                    break;
+                }
+            }
+            Err(e) => match cfg.bind_mode {
+                BindMode::FirstAvailable => {
+                    warn!("Couldn't bind \"{address}\" due to {e}, trying the next address.");
+                }
+                BindMode::All => {
+                    error!("Couldn't bind \"{address}\" due to {e}, and all addresses are required.");
+                    return None;
+                }
+            },
+        }
+    }
+
+    if listeners.is_empty() {
+        error!("None of the bind addresses {:?} could be bound.", cfg.bind_addresses);
+        return None;
+    }
+
+    for address in cfg.metrics.iter().flat_map(|m| &m.listeners) {
+        match bind_listener(address, cfg) {
+            Ok(l) => {
+                info!("Serving metrics on {} (configured as \"{address}\").", l.local_addr);
+                listeners.push(l);
+            }
             Err(e) => {
-                error!("Ran into {e} while trying to set up the listener. Cannot continue.");
-                exit.send(AppExit::default()); // Exit.
-                return;
+                error!("Couldn't bind the metrics listener \"{address}\" due to {e}.");
+                return None;
             }
         }
+    }
+
+    Some(listeners)
+}
+
+/// Serves a connection until it closes. Once a shutdown starts, it's asked to finish the requests it's working on and
+/// then close.
+async fn serve_until_shutdown<I>(
+    http: &Http<BevyExecutor>,
+    io: I,
+    servicer: HttpConnectionServicer,
+    shutdown: watch::Receiver<bool>,
+) -> hyper::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut conn = pin!(http.serve_connection(io, servicer).with_upgrades());
+    let mut shutdown = pin!(wait_for_shutdown(shutdown));
+
+    let finished = poll_fn(|cx| match conn.as_mut().poll(cx) {
+        Poll::Ready(result) => Poll::Ready(Some(result)),
+        Poll::Pending => shutdown.as_mut().poll(cx).map(|()| None),
+    })
+    .await;
+
+    match finished {
+        Some(result) => result,
+        None => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    }
+}
+
+pub(in crate::http) fn http_request_listener_system(
+    mut ctx: ResMut<HttpRequestContext>,
+    cfg: Res<ServiceConfig>,
+    reactor: Res<IoReactor>,
+    tls_ctx: Res<TlsContext>,
+    shutdown: Res<HttpShutdownState>,
+    conn_comp: Query<&HttpConnectionComponent>,
+    sockets: Query<&HttpWebSocket>,
+    renderer: Res<HttpErrorRenderer>,
+    headers: Res<HttpResponseHeaders>,
+    mut stats: ResMut<HttpLoadStats>,
+    mut commands: Commands,
+    mut exit: EventWriter<AppExit>,
+) {
+    if shutdown.is_shutting_down() {
+        // Dropping the listeners closes them, so new connections are refused outright.
+        if !ctx.listeners.is_empty() {
+            info!("No longer accepting connections.");
+            ctx.listeners.clear();
+        }
+        return;
+    }
 
-        ctx.listener.as_ref().unwrap().set_nonblocking(true).expect("Nonblocking unavailable, can't continue!");
+    if ctx.listeners.is_empty() && !ctx.detached {
+        match bind_listeners(&cfg) {
+            Some(l) => ctx.listeners = l,
+            None => {
+                error!("Failed to set up the listeners. Cannot continue.");
+                exit.send(AppExit); // Exit.
+                return;
+            }
+        }
     }
+
     let pool = ComputeTaskPool::get();
+    // An upgraded connection outlives its connection entity, and lives on as its WebSocket.
+    let mut open = conn_comp.iter().count() + sockets.iter().filter(|s| !s.is_closed()).count();
 
-    let listener = ctx.listener.as_ref().unwrap();
-
-    loop {
-        let stream = listener.accept();
-        match stream {
-            Ok((s, addr)) => {
-                info!("Got a connection from {}", addr);
-
-                let _ = s.set_nonblocking(true);
-                let (txreq, rxreq) = mpsc::sync_channel::<Request<Body>>(1);
-                let (txres, rxres) = mpsc::sync_channel::<Result<Response<Body>, Box<dyn Error + Send + Sync>>>(1);
-
-                let task = pool.spawn(async move {
-                    let stream = custtcpstream::CustTcpStream::new(s);
-                    let servicer = HttpSingleServicer::new(txreq, rxres);
-
-                    if let Err(http_err) = Http::new()
-                        .http1_keep_alive(false)
-                        .http2_keep_alive_interval(None)
-                        .serve_connection(stream, servicer)
-                        .await
-                    {
-                        error!("Error while serving HTTP connection: {}", http_err);
+    for HttpListener {
+        listener,
+        local_addr,
+        address,
+        tls,
+        proxy_protocol,
+    } in &ctx.listeners
+    {
+        loop {
+            let stream = listener.accept();
+            match stream {
+                Ok((s, addr)) => {
+                    let accepted = Instant::now();
+                    info!("Got a connection from {addr} on {local_addr}");
+
+                    if open >= cfg.limits.max_connections {
+                        warn!("Turning away {addr}, already at {open} connections.");
+                        stats.connections_rejected += 1;
+                        // There's no cheap way to say no over TLS, so those are just closed.
+                        if !tls {
+                            reject_connection(s, cfg.limits.retry_after_secs);
+                        }
+                        continue;
                     }
-                    info!("(ASYNC) Service adapter done (outside serve).");
-                });
 
-                let request = HttpRequestComponent {
-                    task,
-                    rxreq: Mutex::new(rxreq),
-                    txres,
-                };
+                    let acceptor = match (tls, tls_ctx.acceptor()) {
+                        (false, _) => None,
+                        (true, Some(acceptor)) => Some(acceptor.clone()),
+                        (true, None) => {
+                            warn!("Dropping the connection from {addr}, no TLS certificates are loaded yet.");
+                            continue;
+                        }
+                    };
 
-                let name = format!("HTTP Request {addr}");
+                    let mut stream = match s.register(&reactor) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            error!("Couldn't register the connection from {addr} with the reactor: {e}");
+                            continue;
+                        }
+                    };
+                    let (txreq, rxreq) = request_channel();
+                    let max_requests = cfg.keep_alive.max_requests;
+                    let max_body_size = cfg.limits.max_request_body_size;
+                    let http2 = cfg.http2.clone();
+                    let shutdown = shutdown.subscribe();
+                    let peer = addr.clone();
+                    let proxy_protocol = *proxy_protocol;
+                    let renderer = renderer.clone();
+                    let headers = headers.clone();
 
-                info!("Spawned request entity as \"{name}\"");
+                    let task = pool.spawn(async move {
+                        let proxy_peer = if proxy_protocol {
+                            match read_proxy_header(&mut stream).await {
+                                Ok(addr) => addr,
+                                Err(e) => {
+                                    warn!("Bad PROXY protocol header from {peer}: {e}");
+                                    return;
+                                }
+                            }
+                        } else {
+                            None
+                        };
 
-                commands.spawn(HttpRequestEntityBundle {
-                    request,
-                    name: Name::new(name),
-                });
-            }
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    return;
+                        let servicer = HttpConnectionServicer::new(
+                            txreq,
+                            max_requests,
+                            max_body_size,
+                            proxy_peer,
+                            renderer,
+                            headers,
+                            accepted,
+                        );
+                        let mut http = Http::new().with_executor(BevyExecutor);
+                        http.http1_keep_alive(max_requests > 1)
+                            .http1_only(!http2.enabled)
+                            .http2_keep_alive_interval(None)
+                            .http2_max_concurrent_streams(http2.max_concurrent_streams);
+
+                        let result = match acceptor {
+                            Some(acceptor) => match acceptor.accept(stream).await {
+                                Ok(stream) => serve_until_shutdown(&http, stream, servicer, shutdown).await,
+                                Err(e) => {
+                                    warn!("TLS handshake with {peer} failed: {e}");
+                                    return;
+                                }
+                            },
+                            None => serve_until_shutdown(&http, stream, servicer, shutdown).await,
+                        };
+
+                        if let Err(http_err) = result {
+                            error!("Error while serving HTTP connection: {}", http_err);
+                        }
+                        info!("(ASYNC) Service adapter done (outside serve).");
+                    });
+
+                    let name = format!("HTTP Connection {addr} via {local_addr}");
+                    let connection = HttpConnectionComponent {
+                        task,
+                        rxreq: Mutex::new(rxreq),
+                        peer_addr: addr,
+                        local_addr: local_addr.clone(),
+                        listener: address.clone(),
+                        scheme: if *tls { HttpScheme::Https } else { HttpScheme::Http },
+                        accepted_at: Local::now(),
+                        served: 0,
+                        in_flight: 0,
+                        last_active: Instant::now(),
+                    };
+
+                    info!("Spawned connection entity as \"{name}\"");
+
+                    commands.spawn(HttpConnectionEntityBundle {
+                        connection,
+                        name: Name::new(name),
+                    });
+                    open += 1;
+                    stats.connections_accepted += 1;
+                }
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        // Likely out of file descriptors, which won't clear up by retrying right away.
+                        error!("Couldn't accept a connection on {local_addr}: {e}");
+                    }
+                    break;
                 }
-                error!("{}", e);
             }
         }
     }
+
+    stats.open_connections = open;
 }
@@ -1,86 +1,310 @@
-use http::{Request, Response};
-use hyper::{body::Body, service::Service};
-use log::info;
+use bevy::tasks::ComputeTaskPool;
+use http::header::{CONNECTION, CONTENT_LENGTH};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{body::Body, rt::Executor, service::Service};
+use log::{info, warn};
 use std::fmt::Display;
-use std::sync::mpsc;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
 use std::{
     error::Error,
     future::Future,
     task::{self, Poll},
 };
+use tokio::sync::oneshot;
 
-pub struct HttpSingleServicer {
-    out: Option<mpsc::SyncSender<Request<Body>>>,
-    inp: Option<mpsc::Receiver<Result<Response<Body>, Box<dyn Error + Send + Sync>>>>,
-    done: bool,
+use super::error::{HttpErrorRenderer, HttpHandlerError};
+use super::middleware::HttpResponseHeaders;
+use super::proxy::ProxyProtocolPeer;
+
+/// What error pages rendered here give for the request ID, as requests only get one once they reach the ECS.
+const NO_REQUEST_ID: &str = "-";
+
+/// The result a handler replies to a request with.
+pub type HttpReplyResult = Result<Response<Body>, Box<dyn Error + Send + Sync>>;
+
+/// A request handed off to the ECS with its body fully read, alongside the channel its reply should be sent down.
+pub type HttpIncomingRequest = (Request<Bytes>, oneshot::Sender<HttpReplyResult>);
+
+/// Makes the channel a connection hands its requests off to the ECS through.
+pub(in crate::http) fn request_channel() -> (HttpRequestSender, HttpRequestReceiver) {
+    let (out, inp) = mpsc::channel();
+    let started = Arc::new(AtomicUsize::new(0));
+    (
+        HttpRequestSender {
+            out,
+            started: started.clone(),
+        },
+        HttpRequestReceiver { inp, started },
+    )
 }
 
-impl HttpSingleServicer {
-    pub fn new(
-        out: mpsc::SyncSender<Request<Body>>,
-        inp: mpsc::Receiver<Result<Response<Body>, Box<dyn Error + Send + Sync>>>,
+/// The connection's end of the [`request_channel`]. Requests are counted from when hyper starts on them, so the
+/// connection isn't taken for idle while a body's still being read.
+#[derive(Clone)]
+pub(in crate::http) struct HttpRequestSender {
+    out: mpsc::Sender<HttpIncomingRequest>,
+    /// Requests started on but not yet taken by the ECS.
+    started: Arc<AtomicUsize>,
+}
+
+impl HttpRequestSender {
+    /// Counts a request as started, until it's been taken by the ECS or the returned guard is dropped unsent.
+    fn start(&self) -> StartedRequest {
+        self.started.fetch_add(1, Ordering::SeqCst);
+        StartedRequest {
+            sender: self.clone(),
+            sent: false,
+        }
+    }
+}
+
+/// A request hyper has started on, which counts toward [`HttpRequestReceiver::pending`] until it's sent.
+struct StartedRequest {
+    sender: HttpRequestSender,
+    sent: bool,
+}
+
+impl StartedRequest {
+    /// Hands the request to the ECS, which stops counting it once it takes it. If the ECS side is gone, the request
+    /// is dropped along with its reply channel.
+    fn send(mut self, request: HttpIncomingRequest) {
+        self.sent = self.sender.out.send(request).is_ok();
+    }
+}
+
+impl Drop for StartedRequest {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.sender.started.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// The ECS's end of the [`request_channel`].
+pub(in crate::http) struct HttpRequestReceiver {
+    inp: mpsc::Receiver<HttpIncomingRequest>,
+    started: Arc<AtomicUsize>,
+}
+
+impl HttpRequestReceiver {
+    pub fn try_recv(&self) -> Result<HttpIncomingRequest, mpsc::TryRecvError> {
+        let request = self.inp.try_recv()?;
+        self.started.fetch_sub(1, Ordering::SeqCst);
+        Ok(request)
+    }
+
+    /// How many requests hyper has started on that haven't been taken yet, most likely as their bodies are still
+    /// being read.
+    pub fn pending(&self) -> usize {
+        self.started.load(Ordering::SeqCst)
+    }
+}
+
+/// When a request's latency is counted from, attached to its extensions. That's when the connection was accepted for
+/// its first request, so the handshakes are counted too, and when hyper started on the request for the rest.
+#[derive(Debug, Clone, Copy)]
+pub(in crate::http) struct RequestStarted(pub Instant);
+
+/// Marks a request whose body was over the size limit, attached to its extensions in place of the body. The ECS turns
+/// it away with a 413 (Payload Too Large), so it's logged like any other request.
+#[derive(Debug, Clone, Copy)]
+pub(in crate::http) struct BodyTooLarge;
+
+/// Services every request on a single connection, handing each one off to the ECS as it arrives.
+pub struct HttpConnectionServicer {
+    out: HttpRequestSender,
+    served: usize,
+    max_requests: usize,
+    max_body_size: usize,
+    /// The client address from the connection's PROXY protocol header, if it had one.
+    proxy_peer: Option<SocketAddr>,
+    /// For requests dropped by the ECS without a reply.
+    renderer: HttpErrorRenderer,
+    /// Applied to those replies, as they never pass through the response filters.
+    headers: HttpResponseHeaders,
+    accepted: Instant,
+}
+
+impl HttpConnectionServicer {
+    pub(in crate::http) fn new(
+        out: HttpRequestSender,
+        max_requests: usize,
+        max_body_size: usize,
+        proxy_peer: Option<SocketAddr>,
+        renderer: HttpErrorRenderer,
+        headers: HttpResponseHeaders,
+        accepted: Instant,
     ) -> Self {
         info!("(ASYNC) Service adapter spun up.");
         Self {
-            out: Some(out),
-            inp: Some(inp),
-            done: false,
+            out,
+            served: 0,
+            max_requests,
+            max_body_size,
+            proxy_peer,
+            renderer,
+            headers,
+            accepted,
         }
     }
 }
 
-pub struct HttpSingleServicerFuture<E, R> {
-    inp: mpsc::Receiver<Result<R, E>>,
+/// Why a request body couldn't be read.
+enum BodyReadError {
+    TooLarge,
+    Hyper(hyper::Error),
 }
 
-impl<E, R> HttpSingleServicerFuture<E, R> {
-    pub fn new(inp: mpsc::Receiver<Result<R, E>>) -> Self {
-        HttpSingleServicerFuture { inp }
+/// Reads a request body into memory, giving up as soon as it's known to be larger than `limit`.
+async fn read_body(headers: &HeaderMap, mut body: Body, limit: usize) -> Result<Bytes, BodyReadError> {
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+
+    if declared.is_some_and(|len| len > limit) {
+        return Err(BodyReadError::TooLarge);
+    }
+
+    let mut data = Vec::with_capacity(declared.unwrap_or(0));
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(BodyReadError::Hyper)?;
+        if data.len() + chunk.len() > limit {
+            return Err(BodyReadError::TooLarge);
+        }
+        data.extend_from_slice(&chunk);
     }
+
+    Ok(Bytes::from(data))
 }
 
-impl<E, R> Future for HttpSingleServicerFuture<E, R> {
-    type Output = Result<R, E>;
+/// Resolves once the ECS replies. Sending the reply wakes the future, so nothing is polled while waiting.
+pub struct HttpConnectionServicerFuture {
+    inp: oneshot::Receiver<HttpReplyResult>,
+    /// Whether this is the last request the connection may carry, in which case the reply asks the client to close.
+    close: bool,
+    /// Renders the 500 sent if the request's dropped without a reply, for the request's method and URI.
+    fallback: (HttpErrorRenderer, Method, Uri),
+    /// Applied to that 500, as it never passes through the response filters.
+    headers: HttpResponseHeaders,
+}
 
-    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
-        if let Ok(v) = self.inp.try_recv() {
-            info!("(ASYNC) Task pool sending reply.");
-            return Poll::Ready(v);
+impl HttpConnectionServicerFuture {
+    pub fn new(
+        inp: oneshot::Receiver<HttpReplyResult>,
+        close: bool,
+        fallback: (HttpErrorRenderer, Method, Uri),
+        headers: HttpResponseHeaders,
+    ) -> Self {
+        HttpConnectionServicerFuture {
+            inp,
+            close,
+            fallback,
+            headers,
         }
+    }
+}
 
-        cx.waker().wake_by_ref();
+impl Future for HttpConnectionServicerFuture {
+    type Output = HttpReplyResult;
 
-        return Poll::Pending;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let mut v = match Pin::new(&mut self.inp).poll(cx) {
+            Poll::Ready(Ok(v)) => {
+                info!("(ASYNC) Task pool sending reply.");
+                v
+            }
+            // The request entity went away without replying, which is our fault rather than the client's.
+            Poll::Ready(Err(_)) => {
+                let (renderer, method, uri) = &self.fallback;
+                warn!("(ASYNC) {method} \"{uri}\" failed with {}", RequestFinalizedError());
+                let error = HttpHandlerError::internal(RequestFinalizedError());
+                let mut response = renderer.render_error(&error, NO_REQUEST_ID, method, uri);
+                self.headers.apply(&mut response);
+                Ok(response)
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+
+        // An upgraded connection never goes back to HTTP, and the upgrade needs its own Connection header to go through.
+        if let (true, Ok(response)) = (self.close, &mut v) {
+            if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+                response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+        }
+        Poll::Ready(v)
     }
 }
 
-impl Service<Request<Body>> for HttpSingleServicer {
+impl Service<Request<Body>> for HttpConnectionServicer {
     type Response = Response<Body>;
 
     type Error = Box<dyn Error + Send + Sync>;
 
-    type Future = HttpSingleServicerFuture<Self::Error, Self::Response>;
+    type Future = Pin<Box<dyn Future<Output = HttpReplyResult> + Send>>;
 
-    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        if self.done {
-            info!("(ASYNC) Service adapter done (inside serve).");
-            return Poll::Ready(Err(Box::new(RequestFinalizedError())));
-        }
-        cx.waker().wake_by_ref();
-        return Poll::Ready(Ok(()));
+    fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        self.out
-            .as_mut()
-            .unwrap()
-            .send(req)
-            .expect("Welp, someone screwed up big time, channel is already dead.");
-        self.done = true;
-        return Self::Future::new(self.inp.take().unwrap());
+        self.served += 1;
+        // Connection: close only means something to HTTP/1.x.
+        let close = self.served >= self.max_requests && req.version() < Version::HTTP_2;
+
+        let handoff = self.out.start();
+        let limit = self.max_body_size;
+        let proxy_peer = self.proxy_peer;
+        let renderer = self.renderer.clone();
+        let headers = self.headers.clone();
+        let started = if self.served == 1 { self.accepted } else { Instant::now() };
+
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            if let Some(peer) = proxy_peer {
+                parts.extensions.insert(ProxyProtocolPeer(peer));
+            }
+            parts.extensions.insert(RequestStarted(started));
+
+            let body = match read_body(&parts.headers, body, limit).await {
+                Ok(body) => body,
+                Err(BodyReadError::TooLarge) => {
+                    parts.extensions.insert(BodyTooLarge);
+                    Bytes::new()
+                }
+                Err(BodyReadError::Hyper(e)) => return Err(e.into()),
+            };
+
+            let (txres, rxres) = oneshot::channel::<HttpReplyResult>();
+            let fallback = (renderer, parts.method.clone(), parts.uri.clone());
+            // If the ECS side is gone, txres is dropped here and the future resolves to a 500.
+            handoff.send((Request::from_parts(parts, body), txres));
+
+            HttpConnectionServicerFuture::new(rxres, close, fallback, headers).await
+        })
+    }
+}
+
+/// Runs the background tasks hyper needs (one per HTTP/2 stream) on the compute task pool.
+#[derive(Clone, Copy)]
+pub struct BevyExecutor;
+
+impl<F> Executor<F> for BevyExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        ComputeTaskPool::get().spawn(fut).detach();
     }
 }
 
+/// Raised when a request is dropped by the ECS without ever being replied to.
 #[derive(Debug)]
 pub struct RequestFinalizedError();
 impl Display for RequestFinalizedError {
@@ -90,3 +314,68 @@ impl Display for RequestFinalizedError {
 }
 
 impl Error for RequestFinalizedError {}
+
+#[cfg(test)]
+mod tests {
+    use std::task::{Context, Waker};
+
+    use super::*;
+
+    fn servicer() -> (HttpConnectionServicer, HttpRequestReceiver) {
+        let (out, inp) = request_channel();
+        let servicer = HttpConnectionServicer::new(
+            out,
+            usize::MAX,
+            1024,
+            None,
+            HttpErrorRenderer::default(),
+            HttpResponseHeaders::default(),
+            Instant::now(),
+        );
+        (servicer, inp)
+    }
+
+    #[test]
+    fn counts_a_request_as_pending_while_its_body_is_read() {
+        let (mut servicer, inp) = servicer();
+        let (mut tx, body) = Body::channel();
+        let mut reply = servicer.call(Request::post("/upload").body(body).unwrap());
+        let mut cx = Context::from_waker(Waker::noop());
+
+        tx.try_send_data(Bytes::from_static(b"half")).unwrap();
+        assert!(reply.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(inp.pending(), 1);
+        assert!(inp.try_recv().is_err());
+
+        drop(tx);
+        assert!(reply.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(inp.pending(), 1, "still pending until the ECS takes it");
+        let (request, _txres) = inp.try_recv().unwrap();
+        assert_eq!(request.body().as_ref(), b"half");
+        assert_eq!(inp.pending(), 0);
+    }
+
+    #[test]
+    fn stops_counting_a_request_whose_body_fails() {
+        let (mut servicer, inp) = servicer();
+        let (tx, body) = Body::channel();
+        let mut reply = servicer.call(Request::post("/upload").body(body).unwrap());
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(reply.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(inp.pending(), 1);
+
+        tx.abort();
+        assert!(matches!(reply.as_mut().poll(&mut cx), Poll::Ready(Err(_))));
+        assert_eq!(inp.pending(), 0);
+    }
+
+    #[test]
+    fn stops_counting_a_request_dropped_before_its_body_is_read() {
+        let (mut servicer, inp) = servicer();
+        let reply = servicer.call(Request::get("/").body(Body::empty()).unwrap());
+        assert_eq!(inp.pending(), 1);
+        drop(reply);
+        assert_eq!(inp.pending(), 0);
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bevy::{app::AppExit, prelude::*};
+use log::{info, warn};
+use tokio::sync::watch;
+
+use super::{request::HttpConnectionComponent, websocket::HttpWebSocket};
+use crate::config::ServiceConfig;
+
+/// Event that, when raised, starts a graceful shutdown, the same as SIGTERM or SIGINT would with
+/// [`super::HttpRequestPlugin::handle_signals`] on.
+#[derive(Debug, Default)]
+pub struct HttpShutdownEvent;
+
+/// Tracks a graceful shutdown. Once one starts, the listeners are closed, every connection is told to finish what it's
+/// doing and close, and the app exits once they're all gone or the grace period runs out.
+#[derive(Resource)]
+pub struct HttpShutdownState {
+    /// Set by the signal handlers on the reactor, if there are any.
+    signal: Arc<AtomicBool>,
+    /// Tells the connection tasks to wind down.
+    notify: watch::Sender<bool>,
+    draining_until: Option<Instant>,
+}
+
+impl HttpShutdownState {
+    pub(in crate::http) fn new(signal: Arc<AtomicBool>) -> Self {
+        Self {
+            signal,
+            notify: watch::channel(false).0,
+            draining_until: None,
+        }
+    }
+
+    /// Whether a shutdown is underway. Handlers may use this to avoid starting long-running work.
+    pub fn is_shutting_down(&self) -> bool {
+        self.draining_until.is_some()
+    }
+
+    /// A receiver that flips to true once the shutdown starts, for connection tasks to wait on.
+    pub(in crate::http) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.notify.subscribe()
+    }
+}
+
+/// Resolves once the shutdown starts. Never resolves if the shutdown state is gone.
+pub(in crate::http) async fn wait_for_shutdown(mut notify: watch::Receiver<bool>) {
+    while !*notify.borrow() {
+        if notify.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Starts the shutdown when asked to, and exits once every connection has closed or the grace period is over.
+pub(in crate::http) fn http_shutdown_system(
+    mut state: ResMut<HttpShutdownState>,
+    mut events: EventReader<HttpShutdownEvent>,
+    conn_comp: Query<&HttpConnectionComponent>,
+    websockets: Query<(), With<HttpWebSocket>>,
+    cfg: Res<ServiceConfig>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let requested = events.iter().count() > 0 || state.signal.load(Ordering::Relaxed);
+
+    if requested && !state.is_shutting_down() {
+        let grace = Duration::from_secs(cfg.shutdown.grace_period_secs);
+        info!(
+            "Shutting down, giving {} connection(s) up to {grace:?} to finish.",
+            conn_comp.iter().count()
+        );
+        state.draining_until = Some(Instant::now() + grace);
+        let _ = state.notify.send(true);
+    }
+
+    let Some(until) = state.draining_until else {
+        return;
+    };
+
+    // WebSockets are told to close as soon as shutdown starts, so they hold things up no longer than a close takes.
+    if conn_comp.is_empty() && websockets.is_empty() {
+        info!("All connections are closed, exiting.");
+        exit.send(AppExit);
+    } else if Instant::now() >= until {
+        warn!(
+            "The grace period is over, cutting {} connection(s) short.",
+            conn_comp.iter().count() + websockets.iter().count()
+        );
+        exit.send(AppExit);
+    }
+}
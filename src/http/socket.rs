@@ -0,0 +1,253 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+};
+
+use crate::{custtcpstream::CustTcpStream, reactor::IoReactor};
+
+/// Either end of a connection, over TCP or a unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HttpAddr {
+    Tcp(SocketAddr),
+    /// A unix domain socket. Clients don't usually bind their end to a path, so the peer address rarely has one.
+    Unix(Option<PathBuf>),
+}
+
+impl HttpAddr {
+    /// The IP address, if this is a TCP address.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            HttpAddr::Tcp(addr) => Some(addr.ip()),
+            HttpAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for HttpAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpAddr::Tcp(addr) => write!(f, "{addr}"),
+            HttpAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            HttpAddr::Unix(None) => f.write_str("unix:"),
+        }
+    }
+}
+
+impl From<SocketAddr> for HttpAddr {
+    fn from(addr: SocketAddr) -> Self {
+        HttpAddr::Tcp(addr)
+    }
+}
+
+/// A bound, non-blocking listening socket.
+pub(in crate::http) enum ListenerSocket {
+    Tcp(TcpListener),
+    /// The path is kept so the socket file can be removed once we stop listening.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl ListenerSocket {
+    /// Binds an address from [`crate::config::ServiceConfig::bind_addresses`], either `host:port` or `unix:/path`.
+    pub fn bind(address: &str, unix_mode: Option<u32>) -> io::Result<(Self, HttpAddr)> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Self::bind_unix(Path::new(path), unix_mode);
+        }
+
+        let listener = TcpListener::bind(address)?;
+        let local_addr = listener.local_addr()?;
+        let socket = ListenerSocket::Tcp(listener);
+        socket.set_nonblocking()?;
+        Ok((socket, HttpAddr::Tcp(local_addr)))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &Path, unix_mode: Option<u32>) -> io::Result<(Self, HttpAddr)> {
+        // A socket file left behind by a run that didn't exit cleanly would make binding fail, so clear it out,
+        // as long as it really is a stale socket.
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "a file that isn't a socket is in the way",
+                ));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "something is already listening on the socket",
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = match unix_mode {
+            Some(mode) => Self::bind_unix_with_mode(path, mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        // From here on, dropping the listener cleans the socket file up.
+        let socket = ListenerSocket::Unix(listener, path.to_path_buf());
+        socket.set_nonblocking()?;
+
+        Ok((socket, HttpAddr::Unix(Some(path.to_path_buf()))))
+    }
+
+    /// Binds the socket in a directory only we can get into, and only moves it into place once its mode is set,
+    /// so nobody the mode is meant to keep out can connect in between.
+    #[cfg(unix)]
+    fn bind_unix_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the socket path has no file name"));
+        };
+        // Next to the socket, so the rename doesn't cross filesystems.
+        let staging = dir.join(format!(".bevyblog-bind-{}", std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+        let staged = staging.join(name);
+        let bound = UnixListener::bind(&staged).and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+            fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+        // Only holds the socket file if something above failed.
+        let _ = fs::remove_file(&staged);
+        let _ = fs::remove_dir(&staging);
+        bound
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_: &Path, _: Option<u32>) -> io::Result<(Self, HttpAddr)> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix domain sockets aren't supported on this platform",
+        ))
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            ListenerSocket::Tcp(listener) => listener.set_nonblocking(true),
+            #[cfg(unix)]
+            ListenerSocket::Unix(listener, _) => listener.set_nonblocking(true),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<(AcceptedSocket, HttpAddr)> {
+        match self {
+            ListenerSocket::Tcp(listener) => listener
+                .accept()
+                .map(|(s, addr)| (AcceptedSocket::Tcp(s), HttpAddr::Tcp(addr))),
+            #[cfg(unix)]
+            ListenerSocket::Unix(listener, _) => listener.accept().map(|(s, addr)| {
+                let path = addr.as_pathname().map(Path::to_path_buf);
+                (AcceptedSocket::Unix(s), HttpAddr::Unix(path))
+            }),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ListenerSocket {
+    fn drop(&mut self) {
+        if let ListenerSocket::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A freshly accepted connection, not yet handed to the reactor.
+pub(in crate::http) enum AcceptedSocket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AcceptedSocket {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            AcceptedSocket::Tcp(s) => s.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            AcceptedSocket::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            AcceptedSocket::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            AcceptedSocket::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    /// Hands the socket over to the reactor, after which it's driven by readiness.
+    pub fn register(self, reactor: &IoReactor) -> io::Result<CustTcpStream> {
+        self.set_nonblocking(true)?;
+        match self {
+            AcceptedSocket::Tcp(s) => reactor.register_tcp(s).map(CustTcpStream::Tcp),
+            #[cfg(unix)]
+            AcceptedSocket::Unix(s) => reactor.register_unix(s).map(CustTcpStream::Unix),
+        }
+    }
+}
+
+impl Read for AcceptedSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            AcceptedSocket::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            AcceptedSocket::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for AcceptedSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AcceptedSocket::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            AcceptedSocket::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AcceptedSocket::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            AcceptedSocket::Unix(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_unix_sockets_their_mode_before_they_appear() {
+        let dir = std::env::temp_dir().join(format!("bevyblog-socket-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("http.sock");
+
+        let (socket, _) = ListenerSocket::bind(&format!("unix:{}", path.display()), Some(0o600)).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // Nothing's left of the directory it was bound in.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        UnixStream::connect(&path).unwrap();
+
+        drop(socket);
+        assert!(!path.exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
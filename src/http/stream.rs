@@ -0,0 +1,108 @@
+use std::task::{Context, Poll, Waker};
+
+use bevy::prelude::*;
+use hyper::body::{Bytes, Sender};
+
+/// Attached to a request entity once it's been replied to with [`super::events::HttpRequestReplyEvent::streaming`].
+/// Systems write the body through it over as many frames as they like, and it's flushed out to the connection at the
+/// end of each frame. The request entity sticks around until the stream is finished or the client goes away.
+#[derive(Component)]
+pub struct HttpResponseStream {
+    sender: Option<Sender>,
+    buffer: Vec<u8>,
+    finishing: bool,
+    sent: u64,
+}
+
+impl HttpResponseStream {
+    pub(in crate::http) fn new(sender: Sender) -> Self {
+        Self {
+            sender: Some(sender),
+            buffer: Vec::new(),
+            finishing: false,
+            sent: 0,
+        }
+    }
+
+    /// Queues data to be sent. Anything sent after [`Self::finish`] or once the stream is closed is discarded.
+    pub fn send(&mut self, data: impl AsRef<[u8]>) {
+        if self.sender.is_some() && !self.finishing {
+            self.buffer.extend_from_slice(data.as_ref());
+        }
+    }
+
+    /// How many bytes are queued and haven't been taken by the connection yet.
+    /// This grows when the client reads slower than the handler writes, so handlers should hold off producing more
+    /// while it's large.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// How many bytes have been handed to the connection so far.
+    pub fn bytes_sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Ends the body once everything queued so far has been sent.
+    pub fn finish(&mut self) {
+        self.finishing = true;
+    }
+
+    /// Ends the body immediately and abnormally, so the client can tell the response was cut short.
+    pub fn abort(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            sender.abort();
+        }
+        self.buffer.clear();
+    }
+
+    /// Whether the stream is done, either finished and fully sent, aborted, or dropped by the client.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_none()
+    }
+
+    /// Hands as much of the buffer to the connection as it'll take without blocking.
+    fn flush(&mut self) {
+        let Some(sender) = &mut self.sender else {
+            return;
+        };
+
+        // Nothing here waits on the result, the next frame just tries again.
+        let mut cx = Context::from_waker(Waker::noop());
+        match sender.poll_ready(&mut cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(_)) => {
+                // The client went away.
+                self.sender = None;
+                self.buffer.clear();
+                return;
+            }
+            Poll::Pending => return,
+        }
+
+        if !self.buffer.is_empty() {
+            let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+            let len = chunk.len() as u64;
+            match sender.try_send_data(chunk) {
+                Ok(()) => self.sent += len,
+                Err(chunk) => {
+                    self.buffer = chunk.to_vec();
+                    return;
+                }
+            }
+        }
+
+        if self.finishing {
+            // Dropping the sender ends the body after whatever's already in the channel.
+            self.sender = None;
+        }
+    }
+}
+
+/// Flushes every open response stream. The body channel only holds a single chunk, so whatever was written over the
+/// frame goes out coalesced into one.
+pub(in crate::http) fn http_response_stream_system(mut streams: Query<&mut HttpResponseStream>) {
+    for mut stream in streams.iter_mut() {
+        stream.flush();
+    }
+}
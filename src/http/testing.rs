@@ -0,0 +1,236 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::SocketAddr,
+    pin::pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    asset::{HandleId, LoadState},
+    prelude::*,
+    tasks::ComputeTaskPool,
+};
+use chrono::Local;
+use http::{Request, Response};
+use hyper::{
+    body::{self, Bytes},
+    service::Service,
+    Body,
+};
+
+use super::{
+    error::HttpErrorRenderer,
+    info::HttpScheme,
+    middleware::HttpResponseHeaders,
+    request::{HttpConnectionComponent, HttpRequestContext},
+    service_adapter::{request_channel, HttpConnectionServicer, HttpReplyResult},
+    socket::HttpAddr,
+    HttpRequestPlugin,
+};
+use crate::{
+    config::ServiceConfig,
+    page::{
+        assets::{SiteMapAsset, WebFileAsset},
+        sitemap::SiteMapController,
+        static_page::HttpAssetServeComponent,
+        HttpPageHandlerPlugin,
+    },
+};
+
+/// How many frames in a row the assets have to look settled for, as a freshly loaded site map only gets its pages
+/// set up a frame or two after it's loaded.
+const SETTLED_FRAMES: usize = 3;
+
+/// Drives requests through the whole pipeline without any sockets, for testing sitemaps, routing and handlers.
+///
+/// It holds an [`App`] with the [`HttpRequestPlugin`] and [`HttpPageHandlerPlugin`], which never binds a listener.
+/// Requests are handed in as if they came in on a connection of their own, and the schedule is stepped until they're
+/// replied to:
+///
+/// ```no_run
+/// # use bevyblog::{config::ServiceConfig, http::testing::HttpTestClient};
+/// let config: ServiceConfig = ron::from_str(r#"(sitemaps: ["default.map"], bind_addresses: [])"#).unwrap();
+/// let mut client = HttpTestClient::new(config);
+/// client.wait_for_assets();
+///
+/// let response = client.get("/");
+/// assert_eq!(response.status(), 200);
+/// ```
+///
+/// Add handlers and systems of your own through [`Self::app`]. Only built with the `testing` feature, so turn it on
+/// for the crate in your dev-dependencies.
+pub struct HttpTestClient {
+    app: App,
+    peer_addr: SocketAddr,
+    listener: String,
+    timeout: Duration,
+}
+
+impl HttpTestClient {
+    /// Builds an app serving from `config`. Assets are loaded from the crate's `assets` folder, as usual.
+    pub fn new(config: ServiceConfig) -> Self {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin::default())
+            .add_plugin(AssetPlugin::default())
+            .insert_resource(config)
+            .add_plugin(HttpRequestPlugin::default())
+            .add_plugin(HttpPageHandlerPlugin::default());
+        app.world.resource_mut::<HttpRequestContext>().detach();
+
+        Self {
+            app,
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 40000)),
+            listener: "test".to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sends requests from this address rather than `127.0.0.1:40000`.
+    pub fn with_peer_addr(mut self, peer_addr: SocketAddr) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    /// Sends requests as if they came in on this bind address, as written in the config, rather than `test`.
+    pub fn with_listener(mut self, listener: impl Into<String>) -> Self {
+        self.listener = listener.into();
+        self
+    }
+
+    /// Waits this long for replies and assets before giving up, rather than 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn app(&mut self) -> &mut App {
+        &mut self.app
+    }
+
+    /// Runs the schedule once.
+    pub fn update(&mut self) {
+        self.app.update();
+    }
+
+    /// Steps the schedule until `done` returns true. Panics if it doesn't within the timeout.
+    pub fn update_until(&mut self, what: &str, mut done: impl FnMut(&mut World) -> bool) {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            self.app.update();
+            if done(&mut self.app.world) {
+                return;
+            }
+
+            assert!(Instant::now() < deadline, "Timed out waiting for {what}.");
+            // Assets load on other threads, and deadlines go by the clock.
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Steps the schedule until every site map and the pages it maps have loaded (or failed to).
+    pub fn wait_for_assets(&mut self) {
+        let sitemaps: HashSet<_> = self.app.world.resource::<ServiceConfig>().sitemaps.iter().cloned().collect();
+        let mut settled = 0;
+
+        self.update_until("the assets to load", |world| {
+            let mut controllers = world.query::<&SiteMapController>();
+            let mut servers = world.query::<&HttpAssetServeComponent>();
+            let asset_server = world.resource::<AssetServer>();
+            let failed = |id: HandleId| asset_server.get_load_state(id) == LoadState::Failed;
+
+            let maps = world.resource::<Assets<SiteMapAsset>>();
+            let controllers: Vec<_> = controllers.iter(world).collect();
+            let maps_ready = controllers.len() >= sitemaps.len()
+                && controllers.iter().all(|c| maps.contains(c.map()) || failed(c.map().id()));
+
+            let pages = world.resource::<Assets<WebFileAsset>>();
+            let pages_ready = servers.iter(world).all(|s| pages.contains(s.data()) || failed(s.data().id()));
+
+            settled = if maps_ready && pages_ready { settled + 1 } else { 0 };
+            settled >= SETTLED_FRAMES
+        });
+    }
+
+    /// Sends a request, stepping the schedule until it's replied to. Panics if it isn't within the timeout.
+    ///
+    /// The request goes through the same servicer as one read off a socket, so the body size limit applies and a
+    /// request dropped without a reply gets a 500.
+    pub fn send(&mut self, request: Request<Bytes>) -> HttpReplyResult {
+        let (txreq, rxreq) = request_channel();
+        let cfg = self.app.world.resource::<ServiceConfig>();
+        let mut servicer = HttpConnectionServicer::new(
+            txreq,
+            cfg.keep_alive.max_requests,
+            cfg.limits.max_request_body_size,
+            None,
+            self.app.world.resource::<HttpErrorRenderer>().clone(),
+            self.app.world.resource::<HttpResponseHeaders>().clone(),
+            Instant::now(),
+        );
+
+        // Every request gets a connection of its own, which lives until it's closed for being idle.
+        let peer_addr = HttpAddr::Tcp(self.peer_addr);
+        self.app.world.spawn((
+            HttpConnectionComponent {
+                task: ComputeTaskPool::get().spawn(std::future::pending()),
+                rxreq: Mutex::new(rxreq),
+                peer_addr: peer_addr.clone(),
+                local_addr: HttpAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 80))),
+                listener: self.listener.clone(),
+                scheme: HttpScheme::Http,
+                accepted_at: Local::now(),
+                served: 0,
+                in_flight: 0,
+                last_active: Instant::now(),
+            },
+            Name::new(format!("HTTP Test Connection {peer_addr}")),
+        ));
+
+        let mut reply = pin!(servicer.call(request.map(Body::from)));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut result = None;
+        // Polled after each frame rather than before the first, so the config's been loaded by then.
+        self.update_until("a reply", |_| match reply.as_mut().poll(&mut cx) {
+            Poll::Ready(r) => {
+                result = Some(r);
+                true
+            }
+            Poll::Pending => false,
+        });
+        result.unwrap()
+    }
+
+    /// Reads a reply's body in full, stepping the schedule while it streams in. Panics if the body errors or doesn't
+    /// finish within the timeout.
+    pub fn read_body(&mut self, response: Response<Body>) -> Response<Bytes> {
+        let (parts, body) = response.into_parts();
+        let mut body = pin!(body::to_bytes(body));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut bytes = None;
+        // The body's polled again every frame, so it doesn't need waking.
+        self.update_until("the body", |_| match body.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(b)) => {
+                bytes = Some(b);
+                true
+            }
+            Poll::Ready(Err(e)) => panic!("The body failed to stream: {e}"),
+            Poll::Pending => false,
+        });
+        Response::from_parts(parts, bytes.unwrap())
+    }
+
+    /// Sends a GET request for `uri` and reads the reply in full. Panics if the reply is an error.
+    pub fn get(&mut self, uri: &str) -> Response<Bytes> {
+        let request = Request::get(uri).body(Bytes::new()).unwrap();
+        match self.send(request) {
+            Ok(response) => self.read_body(response),
+            Err(e) => panic!("GET {uri} failed: {e}"),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, error::Error, sync::Arc};
+
+use bevy::{
+    asset::{AssetLoader, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+use log::{error, info};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::ServiceConfig;
+
+/// A PEM encoded file, holding either a certificate chain or a private key.
+#[derive(Debug, TypeUuid)]
+#[uuid = "0b8f6a36-3c1e-4d8e-9a55-2f6f3f4c7d21"]
+pub struct TlsPemAsset {
+    pub data: Vec<u8>,
+}
+
+pub(in crate::http) struct TlsPemLoader();
+
+impl AssetLoader for TlsPemLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            load_context.set_default_asset(LoadedAsset::new(TlsPemAsset { data: bytes.to_vec() }));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["pem", "crt", "key"]
+    }
+}
+
+/// The assets behind one configured certificate.
+struct TlsCertificateHandles {
+    certificate: Handle<TlsPemAsset>,
+    key: Handle<TlsPemAsset>,
+    server_names: Vec<String>,
+}
+
+/// Holds the TLS acceptor new connections are handed to, rebuilt whenever the certificate assets change.
+#[derive(Resource, Default)]
+pub(in crate::http) struct TlsContext {
+    certificates: Vec<TlsCertificateHandles>,
+    acceptor: Option<TlsAcceptor>,
+}
+
+impl TlsContext {
+    /// The acceptor for new connections, or None if the certificates haven't loaded (successfully) yet.
+    pub fn acceptor(&self) -> Option<&TlsAcceptor> {
+        self.acceptor.as_ref()
+    }
+}
+
+/// Picks a certificate by the SNI name the client asked for, falling back to the first configured certificate.
+struct SniCertResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name() else {
+            return Some(self.default.clone());
+        };
+
+        let name = name.to_ascii_lowercase();
+        let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{parent}"));
+
+        self.by_name
+            .get(&name)
+            .or_else(|| wildcard.and_then(|w| self.by_name.get(&w)))
+            .cloned()
+            .or_else(|| Some(self.default.clone()))
+    }
+}
+
+fn parse_certified_key(certificate: &[u8], key: &[u8]) -> Result<CertifiedKey, Box<dyn Error + Send + Sync>> {
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut &*certificate)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if chain.is_empty() {
+        return Err("no certificates in the certificate file".into());
+    }
+
+    let key = rustls_pemfile::read_all(&mut &*key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or("no private key in the key file")?;
+
+    Ok(CertifiedKey::new(chain, sign::any_supported_type(&key)?))
+}
+
+/// Builds an acceptor from the currently loaded certificates, offering h2 through ALPN if HTTP/2 is enabled.
+/// Returns Ok(None) if some of them haven't loaded yet.
+fn build_acceptor(
+    certificates: &[TlsCertificateHandles],
+    assets: &Assets<TlsPemAsset>,
+    http2: bool,
+) -> Result<Option<TlsAcceptor>, Box<dyn Error + Send + Sync>> {
+    let mut default = None;
+    let mut by_name = HashMap::new();
+
+    for handles in certificates {
+        let (Some(certificate), Some(key)) = (assets.get(&handles.certificate), assets.get(&handles.key)) else {
+            return Ok(None);
+        };
+
+        let certified = Arc::new(parse_certified_key(&certificate.data, &key.data)?);
+
+        for name in &handles.server_names {
+            by_name.insert(name.to_ascii_lowercase(), certified.clone());
+        }
+
+        default.get_or_insert(certified);
+    }
+
+    let Some(default) = default else {
+        return Err("no certificates are configured".into());
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniCertResolver { default, by_name }));
+
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Loads the configured certificates, and rebuilds the acceptor whenever they're (re)loaded.
+/// A certificate that fails to parse leaves the previous acceptor in place, so a bad reload doesn't take the site down.
+pub(in crate::http) fn tls_certificate_reloader(
+    cfg: Res<ServiceConfig>,
+    mut ctx: ResMut<TlsContext>,
+    mut asset_events: EventReader<AssetEvent<TlsPemAsset>>,
+    assets: Res<Assets<TlsPemAsset>>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(tls) = &cfg.tls else {
+        return;
+    };
+
+    if ctx.certificates.is_empty() {
+        ctx.certificates = tls
+            .certificates
+            .iter()
+            .map(|c| TlsCertificateHandles {
+                certificate: asset_server.load(c.certificate.as_path()),
+                key: asset_server.load(c.key.as_path()),
+                server_names: c.server_names.clone(),
+            })
+            .collect();
+    }
+
+    let changed = asset_events.iter().any(|ev| match ev {
+        AssetEvent::Created { handle } | AssetEvent::Modified { handle } => ctx
+            .certificates
+            .iter()
+            .any(|c| &c.certificate == handle || &c.key == handle),
+        _ => false,
+    });
+
+    if !changed {
+        return;
+    }
+
+    match build_acceptor(&ctx.certificates, &assets, cfg.http2.enabled) {
+        Ok(Some(acceptor)) => {
+            info!("Loaded {} TLS certificate(s).", ctx.certificates.len());
+            ctx.acceptor = Some(acceptor);
+        }
+        Ok(None) => (), // Still waiting on some of them.
+        Err(e) => error!("Failed to load the TLS certificates, keeping the previous ones: {e}"),
+    }
+}
@@ -0,0 +1,700 @@
+use std::{
+    future::{poll_fn, Future},
+    io,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bevy::{
+    prelude::*,
+    tasks::{ComputeTaskPool, Task},
+};
+use http::{
+    header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE},
+    Method, Request, Response, StatusCode, Version,
+};
+use hyper::{body::Bytes, upgrade::OnUpgrade, Body};
+use log::{info, warn};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc as async_mpsc,
+};
+
+use super::shutdown::HttpShutdownState;
+
+/// Mixed into the client's key to prove the handshake was understood (RFC 6455, section 1.3).
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// How long to wait for the client to answer a close before giving up on it.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many outgoing messages can be waiting on the client before [`HttpWebSocket::send`] starts turning them away.
+const OUTGOING_CAPACITY: usize = 256;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Close codes, from RFC 6455 section 7.4.1.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+}
+
+/// A message sent over a WebSocket. Pings, pongs and closes are dealt with internally.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Bytes),
+}
+
+impl From<String> for WebSocketMessage {
+    fn from(text: String) -> Self {
+        WebSocketMessage::Text(text)
+    }
+}
+
+impl From<&str> for WebSocketMessage {
+    fn from(text: &str) -> Self {
+        WebSocketMessage::Text(text.to_string())
+    }
+}
+
+impl From<Bytes> for WebSocketMessage {
+    fn from(data: Bytes) -> Self {
+        WebSocketMessage::Binary(data)
+    }
+}
+
+impl From<Vec<u8>> for WebSocketMessage {
+    fn from(data: Vec<u8>) -> Self {
+        WebSocketMessage::Binary(data.into())
+    }
+}
+
+/// Raised for every message received on a WebSocket.
+#[derive(Debug)]
+pub struct HttpWebSocketMessageEvent {
+    /// The request entity the WebSocket was accepted on, which holds its [`HttpWebSocket`].
+    pub ent: Entity,
+    pub message: WebSocketMessage,
+}
+
+/// Raised once a WebSocket is closed, by either end. The entity is despawned at the end of the frame.
+#[derive(Debug)]
+pub struct HttpWebSocketClosedEvent {
+    pub ent: Entity,
+}
+
+/// Whether a request asks to be upgraded to a WebSocket, which handlers can accept with
+/// [`super::events::HttpRequestReplyEvent::websocket`]. Only HTTP/1.1 upgrades are supported.
+pub fn is_websocket_upgrade<B>(request: &Request<B>) -> bool {
+    let has_token = |name, token: &str| {
+        request
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+
+    request.version() == Version::HTTP_11
+        && request.method() == Method::GET
+        && has_token(CONNECTION, "upgrade")
+        && has_token(UPGRADE, "websocket")
+        && request.headers().get(SEC_WEBSOCKET_VERSION).is_some_and(|v| v == "13")
+        && request.headers().contains_key(SEC_WEBSOCKET_KEY)
+}
+
+/// The 101 (Switching Protocols) response accepting a WebSocket upgrade, or None if the request isn't one.
+pub(in crate::http) fn handshake_response<B>(request: &Request<B>) -> Option<Response<Body>> {
+    if !is_websocket_upgrade(request) {
+        return None;
+    }
+
+    let key = request.headers().get(SEC_WEBSOCKET_KEY)?;
+    let hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, [key.as_bytes(), HANDSHAKE_GUID.as_bytes()].concat().as_slice());
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_ACCEPT, STANDARD.encode(hash.as_ref()))
+        .body(Body::empty())
+        .ok()
+}
+
+/// What the WebSocket's task is asked to send.
+enum Outgoing {
+    Message(WebSocketMessage),
+    Close(u16, String),
+}
+
+/// Attached to a request entity once its WebSocket upgrade has been accepted, turning it into a long-lived
+/// connection. Messages from the client come in as [`HttpWebSocketMessageEvent`]s, and go out through
+/// [`Self::send`]. The entity sticks around until the WebSocket is closed.
+#[derive(Component)]
+pub struct HttpWebSocket {
+    task: Option<Task<()>>,
+    incoming: Mutex<mpsc::Receiver<WebSocketMessage>>,
+    outgoing: async_mpsc::Sender<Outgoing>,
+    closing_since: Option<Instant>,
+    /// Set once the close event's been raised.
+    reported: bool,
+}
+
+impl HttpWebSocket {
+    /// Spins up the task that takes over the connection once hyper's done with the handshake.
+    pub(in crate::http) fn spawn(upgrade: OnUpgrade, max_message_size: usize) -> Self {
+        let (txin, rxin) = mpsc::channel();
+        let (txout, rxout) = async_mpsc::channel(OUTGOING_CAPACITY);
+
+        let task = ComputeTaskPool::get().spawn(async move {
+            match upgrade.await {
+                Ok(io) => run(io, txin, rxout, max_message_size).await,
+                Err(e) => warn!("WebSocket upgrade failed: {e}"),
+            }
+        });
+
+        Self {
+            task: Some(task),
+            incoming: Mutex::new(rxin),
+            outgoing: txout,
+            closing_since: None,
+            reported: false,
+        }
+    }
+
+    /// Queues a message to be sent. Gives the message back if the WebSocket is closing, or the client isn't keeping
+    /// up and too many are already queued.
+    pub fn send(&mut self, message: impl Into<WebSocketMessage>) -> Result<(), WebSocketMessage> {
+        let message = message.into();
+        if self.closing_since.is_some() {
+            return Err(message);
+        }
+
+        self.outgoing.try_send(Outgoing::Message(message)).map_err(|e| match e {
+            async_mpsc::error::TrySendError::Full(Outgoing::Message(message))
+            | async_mpsc::error::TrySendError::Closed(Outgoing::Message(message)) => message,
+            _ => unreachable!(),
+        })
+    }
+
+    /// Starts closing the WebSocket, after everything already queued has been sent.
+    /// The reason has to fit in a control frame, so it's cut down to 123 bytes.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.closing_since.is_some() {
+            return;
+        }
+
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.closing_since = Some(Instant::now());
+        // If the queue's full, the close goes out once it's drained, and if the task's gone there's nothing to do.
+        let outgoing = self.outgoing.clone();
+        let close = Outgoing::Close(code, reason[..end].to_string());
+        if let Err(async_mpsc::error::TrySendError::Full(close)) = outgoing.try_send(close) {
+            ComputeTaskPool::get()
+                .spawn(async move {
+                    let _ = outgoing.send(close).await;
+                })
+                .detach();
+        }
+    }
+
+    /// Whether the WebSocket is done, closed by either end or dropped by the client.
+    pub fn is_closed(&self) -> bool {
+        self.task.as_ref().is_none_or(|t| t.is_finished())
+    }
+}
+
+/// Hands messages from each WebSocket over as events, and reports the ones that have closed. While shutting down,
+/// every WebSocket is told the server is going away.
+pub(in crate::http) fn http_websocket_system(
+    mut sockets: Query<(Entity, &mut HttpWebSocket)>,
+    shutdown: Res<HttpShutdownState>,
+    mut message_events: EventWriter<HttpWebSocketMessageEvent>,
+    mut closed_events: EventWriter<HttpWebSocketClosedEvent>,
+) {
+    for (ent, mut socket) in sockets.iter_mut() {
+        for message in socket.incoming.lock().unwrap().try_iter() {
+            message_events.send(HttpWebSocketMessageEvent { ent, message });
+        }
+
+        if shutdown.is_shutting_down() {
+            socket.close(close_code::GOING_AWAY, "Server shutting down");
+        }
+
+        if socket.closing_since.is_some_and(|since| since.elapsed() > CLOSE_TIMEOUT) {
+            // Dropping the task closes the connection.
+            socket.task = None;
+        }
+
+        if socket.is_closed() && !socket.reported {
+            socket.reported = true;
+            info!("WebSocket {ent:?} closed.");
+            closed_events.send(HttpWebSocketClosedEvent { ent });
+        }
+    }
+}
+
+/// Drives an upgraded connection until either end closes it.
+async fn run<I>(
+    io: I,
+    incoming: mpsc::Sender<WebSocketMessage>,
+    outgoing: async_mpsc::Receiver<Outgoing>,
+    max_message_size: usize,
+) where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, writer) = tokio::io::split(io);
+    let writer = tokio::sync::Mutex::new(writer);
+    let close_sent = AtomicBool::new(false);
+
+    let mut read = pin!(read_loop(reader, &writer, &close_sent, incoming, max_message_size));
+    let mut write = pin!(write_loop(&writer, &close_sent, outgoing));
+
+    // Reading ends once the client's close comes in, or the connection drops. Writing only ends on an error, having
+    // sent a close it waits for the client's to come back.
+    poll_fn(|cx| {
+        if read.as_mut().poll(cx).is_ready() || write.as_mut().poll(cx).is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+
+    let _ = writer.lock().await.shutdown().await;
+}
+
+async fn read_loop<R, W>(
+    mut reader: R,
+    writer: &tokio::sync::Mutex<W>,
+    close_sent: &AtomicBool,
+    incoming: mpsc::Sender<WebSocketMessage>,
+    max_message_size: usize,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let send_close = |code: u16, reason: &'static str| async move {
+        if !close_sent.swap(true, Ordering::Relaxed) {
+            let _ = write_frame(&mut *writer.lock().await, OP_CLOSE, &close_payload(code, reason)).await;
+        }
+    };
+
+    // The opcode and data of a fragmented message being put back together.
+    let mut partial: Option<(u8, Vec<u8>)> = None;
+
+    loop {
+        let frame = match read_frame(&mut reader, max_message_size).await {
+            Ok(frame) => frame,
+            Err(FrameError::Io(e)) => {
+                info!("WebSocket connection dropped: {e}");
+                return;
+            }
+            Err(FrameError::Protocol(code, reason)) => {
+                send_close(code, reason).await;
+                return;
+            }
+        };
+
+        match frame.opcode {
+            OP_PING => {
+                let _ = write_frame(&mut *writer.lock().await, OP_PONG, &frame.payload).await;
+                continue;
+            }
+            OP_PONG => continue,
+            OP_CLOSE => {
+                // Echo the client's code back, as long as there was one and it's one a client may send.
+                let code = match frame.payload[..] {
+                    [] => close_code::NORMAL,
+                    [high, low, ..] => u16::from_be_bytes([high, low]),
+                    [_] => close_code::PROTOCOL_ERROR,
+                };
+                let code = if is_valid_close_code(code) { code } else { close_code::PROTOCOL_ERROR };
+                send_close(code, "").await;
+                return;
+            }
+            _ => (),
+        }
+
+        let (opcode, mut data) = match (frame.opcode, partial.take()) {
+            (OP_CONTINUATION, Some((opcode, mut data))) => {
+                data.extend_from_slice(&frame.payload);
+                (opcode, data)
+            }
+            (OP_TEXT | OP_BINARY, None) => (frame.opcode, frame.payload),
+            _ => {
+                send_close(close_code::PROTOCOL_ERROR, "Unexpected frame").await;
+                return;
+            }
+        };
+
+        if data.len() > max_message_size {
+            send_close(close_code::TOO_BIG, "Message too big").await;
+            return;
+        }
+
+        if !frame.fin {
+            partial = Some((opcode, std::mem::take(&mut data)));
+            continue;
+        }
+
+        let message = if opcode == OP_TEXT {
+            match String::from_utf8(data) {
+                Ok(text) => WebSocketMessage::Text(text),
+                Err(_) => {
+                    send_close(close_code::INVALID_DATA, "Text isn't valid UTF-8").await;
+                    return;
+                }
+            }
+        } else {
+            WebSocketMessage::Binary(data.into())
+        };
+
+        if incoming.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+async fn write_loop<W>(writer: &tokio::sync::Mutex<W>, close_sent: &AtomicBool, mut outgoing: async_mpsc::Receiver<Outgoing>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(next) = outgoing.recv().await {
+        let (opcode, payload) = match &next {
+            Outgoing::Message(WebSocketMessage::Text(text)) => (OP_TEXT, text.as_bytes()),
+            Outgoing::Message(WebSocketMessage::Binary(data)) => (OP_BINARY, data.as_ref()),
+            Outgoing::Close(code, reason) => {
+                if !close_sent.swap(true, Ordering::Relaxed) {
+                    let _ = write_frame(&mut *writer.lock().await, OP_CLOSE, &close_payload(*code, reason)).await;
+                }
+                break;
+            }
+        };
+
+        if close_sent.load(Ordering::Relaxed) || write_frame(&mut *writer.lock().await, opcode, payload).await.is_err() {
+            return;
+        }
+    }
+
+    // Nothing more to send, so leave it to the reader to see the connection out.
+    std::future::pending::<()>().await
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+enum FrameError {
+    Io(io::Error),
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Reads a single frame from the client, unmasking it.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_message_size: usize) -> Result<Frame, FrameError> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let [first, second] = head;
+
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0F;
+    if first & 0x70 != 0 {
+        return Err(FrameError::Protocol(close_code::PROTOCOL_ERROR, "No extensions were negotiated"));
+    }
+    if second & 0x80 == 0 {
+        return Err(FrameError::Protocol(close_code::PROTOCOL_ERROR, "Client frames must be masked"));
+    }
+
+    let len = match second & 0x7F {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+
+    let control = opcode & 0x8 != 0;
+    if control && (len > 125 || !fin) {
+        return Err(FrameError::Protocol(close_code::PROTOCOL_ERROR, "Bad control frame"));
+    }
+    if len > max_message_size as u64 {
+        return Err(FrameError::Protocol(close_code::TOO_BIG, "Message too big"));
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    Ok(Frame { fin, opcode, payload })
+}
+
+/// Writes a single, unfragmented frame. Frames from the server aren't masked.
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Whether a close frame may carry `code`. Codes that only stand in for a missing one (1005, 1006, 1015) can't be
+/// sent, and neither can anything unassigned in the range the RFC keeps for itself.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    const MAX: usize = 1 << 20;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    /// A frame as a client would send it, masked.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn read(bytes: &[u8], max_message_size: usize) -> Result<Frame, FrameError> {
+        block_on(read_frame(&mut &bytes[..], max_message_size))
+    }
+
+    fn protocol_error(result: Result<Frame, FrameError>) -> u16 {
+        match result {
+            Err(FrameError::Protocol(code, _)) => code,
+            Err(FrameError::Io(e)) => panic!("Expected a protocol error, got {e}"),
+            Ok(_) => panic!("Expected a protocol error, got a frame"),
+        }
+    }
+
+    /// Runs the read loop over everything the client sent, giving the messages it passed on and the frames it wrote
+    /// back, as `(opcode, payload)`.
+    fn converse(frames: &[Vec<u8>], max_message_size: usize) -> (Vec<WebSocketMessage>, Vec<(u8, Vec<u8>)>) {
+        let input = frames.concat();
+        let writer = tokio::sync::Mutex::new(Vec::new());
+        let close_sent = AtomicBool::new(false);
+        let (txin, rxin) = mpsc::channel();
+        block_on(read_loop(&input[..], &writer, &close_sent, txin, max_message_size));
+
+        let written = writer.into_inner();
+        let mut replies = Vec::new();
+        let mut rest = &written[..];
+        while !rest.is_empty() {
+            let len = match rest[1] {
+                126 => usize::from(u16::from_be_bytes([rest[2], rest[3]])),
+                len => usize::from(len),
+            };
+            let start = if rest[1] == 126 { 4 } else { 2 };
+            replies.push((rest[0] & 0x0F, rest[start..start + len].to_vec()));
+            rest = &rest[start + len..];
+        }
+        (rxin.try_iter().collect(), replies)
+    }
+
+    #[test]
+    fn accepts_the_rfc_handshake() {
+        let request = Request::get("/chat")
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+
+        let response = handshake_response(&request).unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers()[SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let plain = Request::get("/chat").body(()).unwrap();
+        assert!(handshake_response(&plain).is_none());
+    }
+
+    #[test]
+    fn unmasks_frames() {
+        let frame = read(&client_frame(true, OP_TEXT, b"Hello"), MAX).ok().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        let mut frame = client_frame(true, OP_TEXT, b"Hello");
+        frame[1] &= 0x7F;
+        assert_eq!(protocol_error(read(&frame, MAX)), close_code::PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn reads_extended_lengths() {
+        for len in [125, 126, 0xFFFF, 0x10000, 70000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = client_frame(true, OP_BINARY, &payload);
+            assert_eq!(read(&frame, MAX).ok().unwrap().payload, payload, "with {len} bytes");
+        }
+    }
+
+    #[test]
+    fn writes_extended_lengths() {
+        for (len, head) in [(125, vec![0x82, 125]), (126, vec![0x82, 126, 0, 126]), (70000, vec![0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70])] {
+            let mut out = Vec::new();
+            block_on(write_frame(&mut out, OP_BINARY, &vec![0; len])).unwrap();
+            assert_eq!(out[..head.len()], head, "with {len} bytes");
+            assert_eq!(out.len(), head.len() + len);
+        }
+    }
+
+    #[test]
+    fn rejects_oversize_frames() {
+        assert_eq!(protocol_error(read(&client_frame(true, OP_BINARY, &[0; 200]), 100)), close_code::TOO_BIG);
+
+        // Turned away on the length alone, without waiting for a payload that's never coming.
+        let huge = [0x82, 0xFF, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(protocol_error(read(&huge, MAX)), close_code::TOO_BIG);
+    }
+
+    #[test]
+    fn rejects_bad_control_frames() {
+        assert_eq!(protocol_error(read(&client_frame(true, OP_PING, &[0; 126]), MAX)), close_code::PROTOCOL_ERROR);
+        assert_eq!(protocol_error(read(&client_frame(false, OP_PING, b"hi"), MAX)), close_code::PROTOCOL_ERROR);
+        assert!(read(&client_frame(true, OP_PING, &[0; 125]), MAX).is_ok());
+    }
+
+    #[test]
+    fn reassembles_fragmented_messages() {
+        let (messages, replies) = converse(
+            &[
+                client_frame(false, OP_TEXT, b"Hel"),
+                // Control frames may come in the middle of a fragmented message.
+                client_frame(true, OP_PING, b"ping"),
+                client_frame(false, OP_CONTINUATION, b"lo, "),
+                client_frame(true, OP_CONTINUATION, b"world"),
+                client_frame(true, OP_BINARY, &[1, 2, 3]),
+                client_frame(true, OP_CLOSE, &close_payload(close_code::NORMAL, "bye")),
+            ],
+            MAX,
+        );
+
+        assert_eq!(messages, [WebSocketMessage::Text("Hello, world".to_string()), WebSocketMessage::Binary(vec![1, 2, 3].into())]);
+        assert_eq!(replies, [(OP_PONG, b"ping".to_vec()), (OP_CLOSE, close_payload(close_code::NORMAL, ""))]);
+    }
+
+    #[test]
+    fn rejects_out_of_place_fragments() {
+        // A continuation with nothing to continue.
+        let (messages, replies) = converse(&[client_frame(true, OP_CONTINUATION, b"lo")], MAX);
+        assert!(messages.is_empty());
+        assert_eq!(replies[0].1[..2], close_code::PROTOCOL_ERROR.to_be_bytes());
+
+        // A new message before the last one's finished.
+        let (messages, replies) = converse(&[client_frame(false, OP_TEXT, b"Hel"), client_frame(true, OP_TEXT, b"lo")], MAX);
+        assert!(messages.is_empty());
+        assert_eq!(replies[0].1[..2], close_code::PROTOCOL_ERROR.to_be_bytes());
+    }
+
+    #[test]
+    fn limits_the_size_of_fragmented_messages() {
+        let (messages, replies) = converse(
+            &[client_frame(false, OP_BINARY, &[0; 60]), client_frame(true, OP_CONTINUATION, &[0; 60])],
+            100,
+        );
+        assert!(messages.is_empty());
+        assert_eq!(replies[0].1[..2], close_code::TOO_BIG.to_be_bytes());
+    }
+
+    #[test]
+    fn echoes_valid_close_codes() {
+        for code in [close_code::NORMAL, close_code::GOING_AWAY, 1011, 3000, 4999] {
+            let (_, replies) = converse(&[client_frame(true, OP_CLOSE, &close_payload(code, "bye"))], MAX);
+            assert_eq!(replies, [(OP_CLOSE, close_payload(code, ""))]);
+        }
+
+        let (_, replies) = converse(&[client_frame(true, OP_CLOSE, &[])], MAX);
+        assert_eq!(replies, [(OP_CLOSE, close_payload(close_code::NORMAL, ""))]);
+    }
+
+    #[test]
+    fn rejects_invalid_close_codes() {
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, u16::MAX] {
+            let (_, replies) = converse(&[client_frame(true, OP_CLOSE, &close_payload(code, ""))], MAX);
+            assert_eq!(replies, [(OP_CLOSE, close_payload(close_code::PROTOCOL_ERROR, ""))], "for {code}");
+        }
+
+        // Half a code.
+        let (_, replies) = converse(&[client_frame(true, OP_CLOSE, &[0x03])], MAX);
+        assert_eq!(replies, [(OP_CLOSE, close_payload(close_code::PROTOCOL_ERROR, ""))]);
+    }
+
+    #[test]
+    fn rejects_invalid_text() {
+        let (messages, replies) = converse(&[client_frame(true, OP_TEXT, &[0xC3, 0x28])], MAX);
+        assert!(messages.is_empty());
+        assert_eq!(replies[0].1[..2], close_code::INVALID_DATA.to_be_bytes());
+    }
+}
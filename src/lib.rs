@@ -0,0 +1,8 @@
+// Bevy systems take everything they use as arguments, so they run long, and their queries get wordy.
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
+pub mod config;
+mod custtcpstream;
+pub mod http;
+pub mod page;
+mod reactor;
@@ -2,13 +2,9 @@ use bevy::{
     app::{ScheduleRunnerPlugin, ScheduleRunnerSettings},
     prelude::*, log::{LogPlugin, Level},
 };
-use std::{error::Error, time::Duration, path::{PathBuf, Path}, fs::File, io::Read};
+use std::{error::Error, time::Duration, fs::File, io::Read};
 
-use crate::{page::{static_page::HttpAssetServeBundle, assets::SiteMapAsset}, config::ServiceConfig};
-mod custtcpstream;
-mod config;
-mod http;
-mod page;
+use bevyblog::{config::ServiceConfig, http, page};
 
 fn main() -> Result<(), Box<dyn Error>> {
     //setup_logger()?;
@@ -35,11 +31,10 @@ fn main() -> Result<(), Box<dyn Error>> {
         })
         .insert_resource(config.clone())
         .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_micros(8333))) // I think only responding in 8ms periods is fine. This brings the CPU use from 100% to 0.1%. I'm not kidding.
-        .add_plugin(ScheduleRunnerPlugin::default())
-        .add_plugin(http::HttpRequestPlugin::default())
+        .add_plugin(ScheduleRunnerPlugin)
+        .add_plugin(http::HttpRequestPlugin { handle_signals: true })
         .add_plugin(page::HttpPageHandlerPlugin::default());
     
-    let world = &mut app.world;
     app.run();
     Ok(())
 }
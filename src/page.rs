@@ -2,6 +2,9 @@ pub mod pathspec;
 pub mod error_replies;
 pub mod static_page;
 pub mod assets;
+pub mod compression;
 pub mod sitemap;
+pub mod rate_limit;
+pub mod sse;
 mod plugin;
 pub use plugin::HttpPageHandlerPlugin;
\ No newline at end of file
@@ -1,23 +1,67 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use bevy::asset::{AssetLoader, LoadedAsset};
 use bevy::reflect::{TypeUuid};
 use hyper::body::Bytes;
 use serde::Deserialize;
 
+use crate::config::CompressionConfig;
+
+use super::compression::{encode_variants, EncodedVariant};
+
 #[derive(Debug, TypeUuid)]
 #[uuid="5dadb1ea-82d0-40da-b864-596f8b2b40b7"]
 pub struct WebFileAsset {
     pub data: Bytes,
+    /// `data` compressed ahead of time, most preferred encoding first. Empty if it isn't worth compressing.
+    pub encoded: Vec<EncodedVariant>,
+}
+
+/// Guesses the MIME type of a file from its extension.
+pub(in super) fn mime_type_of(file_path: &Path) -> &'static str {
+    file_path
+        .extension()
+        .and_then(|v| v.to_str())
+        .and_then(|v| mime_guess::from_ext(v).first_raw())
+        .unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM.essence_str())
 }
 
 #[derive(Debug, TypeUuid, Deserialize)]
 #[uuid="c5c61281-cddb-47eb-9e76-d1c73a75105f"]
 pub struct SiteMapAsset {
-    pub mapping: Vec<(PathBuf, PathBuf)>
+    pub mapping: Vec<(PathBuf, PathBuf)>,
+    /// Settings for requests matching a path pattern, which may also cover handlers that aren't in this map.
+    /// The first matching pattern wins.
+    #[serde(default)]
+    pub routes: Vec<(PathBuf, RouteSettings)>,
+}
+
+/// Per route overrides for the service wide settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RouteSettings {
+    /// Overrides [`crate::config::LimitsConfig::request_timeout_secs`].
+    pub request_timeout_secs: Option<u64>,
+    /// Rate limits the route on its own, separately from the rest of the site. Applies even if rate limiting is
+    /// otherwise disabled.
+    pub rate_limit: Option<RouteRateLimit>,
+    /// Headers added to responses on the route that don't already have them. These take precedence over
+    /// [`crate::config::HeadersConfig::add`]. Requests turned away before they're routed, for a body that's too large
+    /// or the request queue being full, don't get them.
+    pub headers: Vec<(String, String)>,
+}
+
+/// A rate limit for a single route, see [`crate::config::RateLimitConfig`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteRateLimit {
+    #[serde(deserialize_with = "crate::config::positive_rate")]
+    pub requests_per_second: f64,
+    #[serde(deserialize_with = "crate::config::positive_burst")]
+    pub burst: u32,
 }
 
-pub(in super) struct WebFileLoader();
+/// Loads files to be served as they are, compressing them as the [`CompressionConfig`] says to.
+pub(in super) struct WebFileLoader(pub(in super) CompressionConfig);
 
 impl AssetLoader for WebFileLoader {
     fn load<'a>(
@@ -26,7 +70,8 @@ impl AssetLoader for WebFileLoader {
         load_context: &'a mut bevy::asset::LoadContext,
     ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
         Box::pin(async move {
-            load_context.set_default_asset(LoadedAsset::new(WebFileAsset { data: Bytes::copy_from_slice(bytes) }));
+            let encoded = encode_variants(bytes, mime_type_of(load_context.path()), &self.0);
+            load_context.set_default_asset(LoadedAsset::new(WebFileAsset { data: Bytes::copy_from_slice(bytes), encoded }));
             Ok(())
         })
     }
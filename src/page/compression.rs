@@ -0,0 +1,181 @@
+use std::io::Write;
+
+use bevy::prelude::*;
+use flate2::{write::GzEncoder, Compression};
+use http::HeaderValue;
+use hyper::body::Bytes;
+
+use crate::config::{CompressionConfig, ContentEncoding};
+
+/// An asset compressed with one of the [`CompressionConfig::encodings`].
+#[derive(Debug)]
+pub struct EncodedVariant {
+    pub encoding: ContentEncoding,
+    pub data: Bytes,
+}
+
+/// Whether `mimetype` is on the [`CompressionConfig::mime_types`] allowlist.
+fn is_compressible(mimetype: &str, cfg: &CompressionConfig) -> bool {
+    cfg.mime_types.iter().any(|allowed| match allowed.strip_suffix('*') {
+        Some(prefix) => mimetype.starts_with(prefix),
+        None => mimetype.eq_ignore_ascii_case(allowed),
+    })
+}
+
+fn compress(data: &[u8], encoding: ContentEncoding) -> std::io::Result<Vec<u8>> {
+    // These only run when an asset is (re)loaded, so they may as well squeeze out every byte.
+    match encoding {
+        ContentEncoding::Brotli => {
+            let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+            writer.write_all(data)?;
+            Ok(writer.into_inner())
+        }
+        ContentEncoding::Zstd => zstd::bulk::compress(data, 19),
+        ContentEncoding::Gzip => {
+            let mut writer = GzEncoder::new(Vec::new(), Compression::best());
+            writer.write_all(data)?;
+            writer.finish()
+        }
+    }
+}
+
+/// Compresses an asset with every configured encoding, in order of preference. Returns nothing if the asset is too
+/// small or of a type that isn't worth compressing, and leaves out any encoding that doesn't make it smaller.
+pub(in super) fn encode_variants(data: &[u8], mimetype: &str, cfg: &CompressionConfig) -> Vec<EncodedVariant> {
+    if !cfg.enabled || data.len() < cfg.min_size || !is_compressible(mimetype, cfg) {
+        return Vec::new();
+    }
+
+    cfg.encodings
+        .iter()
+        .filter_map(|&encoding| match compress(data, encoding) {
+            Ok(compressed) if compressed.len() < data.len() => Some(EncodedVariant {
+                encoding,
+                data: Bytes::from(compressed),
+            }),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Failed to compress an asset with {}, error is: {e}", encoding.token());
+                None
+            }
+        })
+        .collect()
+}
+
+/// Picks the variant to send for a request's `Accept-Encoding`, going by the client's q-values and breaking ties in
+/// the order the variants are in. `None` means sending the asset as it is.
+pub(in super) fn negotiate<'a>(accept: Option<&HeaderValue>, variants: &'a [EncodedVariant]) -> Option<&'a EncodedVariant> {
+    let accept = accept?.to_str().ok()?;
+
+    let mut explicit: Vec<(&str, f32)> = Vec::new();
+    for item in accept.split(',') {
+        let mut params = item.split(';').map(str::trim);
+        let coding = params.next().unwrap_or_default();
+        if coding.is_empty() {
+            continue;
+        }
+
+        let q = match params.find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q="))) {
+            Some(q) => match q.parse::<f32>() {
+                Ok(q) if (0.0..=1.0).contains(&q) => q,
+                _ => continue,
+            },
+            None => 1.0,
+        };
+        explicit.push((coding, q));
+    }
+
+    let q_of = |coding: &str| {
+        explicit.iter().find(|(c, _)| c.eq_ignore_ascii_case(coding)).map(|(_, q)| *q)
+    };
+    let wildcard = q_of("*");
+    // Sending it as it is is always acceptable, but only wins out over an encoding the client gives a lower q-value
+    // to if it gives one to identity too, directly or through the wildcard.
+    let identity = q_of("identity").or(wildcard);
+
+    let mut best: Option<(&EncodedVariant, f32)> = None;
+    for variant in variants {
+        let q = match variant.encoding {
+            ContentEncoding::Gzip => q_of("gzip").or_else(|| q_of("x-gzip")),
+            encoding => q_of(encoding.token()),
+        }
+        .or(wildcard)
+        .unwrap_or(0.0);
+
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((variant, q));
+        }
+    }
+
+    best.filter(|(_, q)| identity.is_none_or(|identity| *q >= identity)).map(|(variant, _)| variant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variants(encodings: &[ContentEncoding]) -> Vec<EncodedVariant> {
+        encodings.iter().map(|&encoding| EncodedVariant { encoding, data: Bytes::new() }).collect()
+    }
+
+    /// The encoding picked for `accept`, out of brotli, zstd and gzip in that order, or `None` for sending it as is.
+    fn pick(accept: &str) -> Option<ContentEncoding> {
+        pick_from(accept, &[ContentEncoding::Brotli, ContentEncoding::Zstd, ContentEncoding::Gzip])
+    }
+
+    fn pick_from(accept: &str, encodings: &[ContentEncoding]) -> Option<ContentEncoding> {
+        let variants = variants(encodings);
+        negotiate(Some(&HeaderValue::from_str(accept).unwrap()), &variants).map(|v| v.encoding)
+    }
+
+    #[test]
+    fn prefers_higher_q_values() {
+        assert_eq!(pick("gzip;q=0.9, br;q=0.8"), Some(ContentEncoding::Gzip));
+        assert_eq!(pick("br;q=0.2, zstd;q=0.3, gzip;q=0.1"), Some(ContentEncoding::Zstd));
+        assert_eq!(pick("gzip, br;q=0.5"), Some(ContentEncoding::Gzip));
+    }
+
+    #[test]
+    fn breaks_ties_in_config_order() {
+        assert_eq!(pick("gzip, br"), Some(ContentEncoding::Brotli));
+        assert_eq!(pick_from("gzip, br", &[ContentEncoding::Gzip, ContentEncoding::Brotli]), Some(ContentEncoding::Gzip));
+    }
+
+    #[test]
+    fn accepts_x_gzip() {
+        assert_eq!(pick("x-gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(pick("X-GZIP;q=0.5"), Some(ContentEncoding::Gzip));
+    }
+
+    #[test]
+    fn wildcards_cover_everything_not_named() {
+        assert_eq!(pick("*"), Some(ContentEncoding::Brotli));
+        assert_eq!(pick("br;q=0, *;q=0.5"), Some(ContentEncoding::Zstd));
+        assert_eq!(pick("*;q=0"), None);
+    }
+
+    #[test]
+    fn only_compresses_when_better_than_identity() {
+        assert_eq!(pick("gzip;q=0.5, identity"), None);
+        assert_eq!(pick("gzip;q=0.5, identity;q=0.5"), Some(ContentEncoding::Gzip));
+        assert_eq!(pick("gzip;q=0.1, identity;q=0"), Some(ContentEncoding::Gzip));
+        // Nothing else is acceptable either, so it goes out as it is all the same.
+        assert_eq!(pick("identity;q=0"), None);
+    }
+
+    #[test]
+    fn skips_malformed_q_values() {
+        assert_eq!(pick("br;q=abc, gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(pick("br;q=2, gzip;q="), None);
+        assert_eq!(pick("br;Q=0.5"), Some(ContentEncoding::Brotli));
+        assert_eq!(pick(" , ;q=1, zstd"), Some(ContentEncoding::Zstd));
+    }
+
+    #[test]
+    fn sends_as_is_without_accept_encoding() {
+        let variants = variants(&[ContentEncoding::Gzip]);
+        assert!(negotiate(None, &variants).is_none());
+        assert_eq!(pick("deflate"), None);
+        assert_eq!(pick_from("gzip", &[]), None);
+    }
+}
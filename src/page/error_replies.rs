@@ -1,40 +1,44 @@
 use std::sync::Arc;
 
 use bevy::prelude::*;
-use http::{Response, StatusCode, Request};
+use http::{StatusCode, Request, HeaderValue, header::RETRY_AFTER};
 use log::warn;
-use hyper::Body;
+use hyper::body::Bytes;
 
-use crate::http::events::HttpRequestReplyEvent;
+use crate::http::{error::HttpHandlerError, events::HttpRequestReplyEvent, info::HttpRequestId};
+
+/// Replies with an error page, rendered by the [`crate::http::error::HttpErrorRenderer`] like any other.
+fn reply_error(events: &mut EventWriter<HttpRequestReplyEvent>, error: HttpHandlerError, request: Entity) {
+    events.send(HttpRequestReplyEvent::new(Err(Box::new(error)), request))
+}
 
 /// Automatically reply to the given request with the 404 (Not Found) page.
-pub fn reply_request_404(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Body>>, request: Entity) {
-    warn!("Replying 404 to request for \"{:?}\".", request_data.uri());
-    let mut response = Response::new(Body::from("404 Not Found."));
-    let _ = std::mem::replace(response.status_mut(), StatusCode::NOT_FOUND); // why do i have to do it this way.
-    events.send(HttpRequestReplyEvent::new(Ok(response), request))
+pub fn reply_request_404(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Bytes>>, request: Entity) {
+    warn!("[{}] Replying 404 to request for \"{:?}\".", HttpRequestId::of(&request_data).unwrap_or("-"), request_data.uri());
+    reply_error(events, HttpHandlerError::new(StatusCode::NOT_FOUND), request)
 }
 
 /// Automatically reply to the given request with the 400 (Bad Request) page.
-pub fn reply_request_400(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Body>>, request: Entity) {
-    warn!("Replying 400 to request for \"{:?}\".", request_data.uri());
-    let mut response = Response::new(Body::from("400 Bad Request."));
-    let _ = std::mem::replace(response.status_mut(), StatusCode::BAD_REQUEST); // why do i have to do it this way.
-    events.send(HttpRequestReplyEvent::new(Ok(response), request))
+pub fn reply_request_400(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Bytes>>, request: Entity) {
+    warn!("[{}] Replying 400 to request for \"{:?}\".", HttpRequestId::of(&request_data).unwrap_or("-"), request_data.uri());
+    reply_error(events, HttpHandlerError::new(StatusCode::BAD_REQUEST), request)
+}
+
+/// Automatically reply to the given request with the 429 (Too Many Requests) page, asking the client to come back after the given number of seconds.
+pub fn reply_request_429(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Bytes>>, request: Entity, retry_after_secs: u64) {
+    warn!("[{}] Replying 429 to request for \"{:?}\".", HttpRequestId::of(&request_data).unwrap_or("-"), request_data.uri());
+    let error = HttpHandlerError::new(StatusCode::TOO_MANY_REQUESTS).with_header(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    reply_error(events, error, request)
 }
 
 /// Automatically reply to the given request with the 503 (Service Unavailable) page.
-pub fn reply_request_503(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Body>>, request: Entity) {
-    warn!("Replying 503 to request for \"{:?}\".", request_data.uri());
-    let mut response = Response::new(Body::from("503 Service Unavailable."));
-    let _ = std::mem::replace(response.status_mut(), StatusCode::SERVICE_UNAVAILABLE); // why do i have to do it this way.
-    events.send(HttpRequestReplyEvent::new(Ok(response), request))
+pub fn reply_request_503(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Bytes>>, request: Entity) {
+    warn!("[{}] Replying 503 to request for \"{:?}\".", HttpRequestId::of(&request_data).unwrap_or("-"), request_data.uri());
+    reply_error(events, HttpHandlerError::new(StatusCode::SERVICE_UNAVAILABLE), request)
 }
 
 /// Automatically reply to the given request with the 500 (Internal Server Error) page.
-pub fn reply_request_500(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Body>>, request: Entity) {
-    warn!("Replying 500 to request for \"{:?}\".", request_data.uri());
-    let mut response = Response::new(Body::from("500 Internal Server Error."));
-    let _ = std::mem::replace(response.status_mut(), StatusCode::SERVICE_UNAVAILABLE); // why do i have to do it this way.
-    events.send(HttpRequestReplyEvent::new(Ok(response), request))
-}
\ No newline at end of file
+pub fn reply_request_500(events: &mut EventWriter<HttpRequestReplyEvent>, request_data: Arc<Request<Bytes>>, request: Entity) {
+    warn!("[{}] Replying 500 to request for \"{:?}\".", HttpRequestId::of(&request_data).unwrap_or("-"), request_data.uri());
+    reply_error(events, HttpHandlerError::new(StatusCode::INTERNAL_SERVER_ERROR), request)
+}
@@ -1,12 +1,12 @@
-use std::{path::{PathBuf, Path}, sync::Arc, ffi::OsStr, collections::HashMap};
+use std::{path::{PathBuf, Path}, sync::Arc, ffi::OsStr, time::Duration};
 
 use bevy::prelude::*;
 use http::{Request};
-use hyper::Body;
+use hyper::body::Bytes;
 
-use crate::http::events::{HttpRequestReceivedEvent, HttpRequestReplyEvent};
+use crate::http::{deadline::HttpRequestDeadline, info::HttpRequestInfo, events::{HttpRequestReceivedEvent, HttpRequestReplyEvent}, metrics::{HttpMetrics, HttpMetricsRoute}, shutdown::HttpShutdownState};
 
-use super::error_replies::reply_request_404;
+use super::{error_replies::{reply_request_404, reply_request_503}, sitemap::HttpRouteTable};
 
 /// A path specifier for the entity, which when combined with a mailbox allows standard pathed requests to be routed to it.
 #[derive(Component)]
@@ -15,19 +15,19 @@ pub struct HttpHandlerPathSpec {
 }
 
 impl HttpHandlerPathSpec {
-    pub fn extension<'a>(&'a self) -> Option<&'a str> {
+    pub fn extension(&self) -> Option<&str> {
         self.path.extension().and_then(|v| v.to_str())
     }
 
-    pub fn path<'a>(&'a self) -> &'a Path {
+    pub fn path(&self) -> &Path {
         &self.path
     }
 }
 
-/// A mailbox for requests, indicating where they're from and their body. Use with a path specifier.
-#[derive(Component)]
+/// A mailbox for requests, indicating where they're from, their body and their [`HttpRequestInfo`]. Use with a path specifier.
+#[derive(Component, Default)]
 pub struct HttpHandlerRequestMailbox {
-    mailbox: Vec<(Entity, Arc<Request<Body>>)>,
+    mailbox: Vec<(Entity, Arc<Request<Bytes>>, HttpRequestInfo)>,
 }
 
 impl HttpHandlerRequestMailbox {
@@ -37,12 +37,21 @@ impl HttpHandlerRequestMailbox {
         }
     }
 
-    pub fn read_message(&mut self) -> Option<(Entity, Arc<Request<Body>>)> {
+    pub fn read_message(&mut self) -> Option<(Entity, Arc<Request<Bytes>>, HttpRequestInfo)> {
         self.mailbox.pop()
     }
 
-    pub fn push_message(&mut self, handler: Entity, msg: Arc<Request<Body>>) {
-        self.mailbox.push((handler, msg));
+    pub fn push_message(&mut self, handler: Entity, msg: Arc<Request<Bytes>>, info: HttpRequestInfo) {
+        self.mailbox.push((handler, msg, info));
+    }
+
+    /// How many requests are waiting to be read.
+    pub fn len(&self) -> usize {
+        self.mailbox.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mailbox.is_empty()
     }
 }
 
@@ -88,35 +97,71 @@ pub fn check_path_matches(check: &Path, pattern: &Path) -> bool {
         }
     }
 
-    return true;
+    true
 }
  
+/// Every handler's pattern, in the order the handlers were registered. A path goes to the first handler it matches.
 #[derive(Resource, Default)]
 pub(in super) struct PathSpecSearcherResource {
-    path_set: HashMap<Entity, PathBuf> // Wiring up the logic to have an acceleration structure, and then not actually doing it. Classic.
+    path_set: Vec<(Entity, PathBuf)> // Wiring up the logic to have an acceleration structure, and then not actually doing it. Classic.
+}
+
+impl PathSpecSearcherResource {
+    fn register(&mut self, entity: Entity, pattern: PathBuf) {
+        match self.path_set.iter_mut().find(|(e, _)| *e == entity) {
+            Some((_, existing)) => *existing = pattern,
+            None => self.path_set.push((entity, pattern)),
+        }
+    }
+
+    /// The handlers the given path matches, in the order they get first pick.
+    fn matches<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = (Entity, &'a Path)> {
+        self.path_set.iter().filter(|(_, pattern)| check_path_matches(path, pattern)).map(|(e, p)| (*e, p.as_path()))
+    }
+
+    /// Finds the pattern of a handler the given path would be routed to, if any.
+    pub(in super) fn find_pattern(&self, path: &Path) -> Option<&Path> {
+        self.path_set.iter().find(|(_, pattern)| check_path_matches(path, pattern)).map(|(_, p)| p.as_path())
+    }
 }
 
 pub(in super) fn http_request_sorter_system(
-    modified_path_specs: Query<(Entity, &HttpHandlerPathSpec, Changed<HttpHandlerPathSpec>)>,
+    modified_path_specs: Query<(Entity, &HttpHandlerPathSpec), Changed<HttpHandlerPathSpec>>,
+    removed_path_specs: RemovedComponents<HttpHandlerPathSpec>,
     mut path_mailboxes: Query<(Entity, &mut HttpHandlerRequestMailbox)>,
+    routes: Res<HttpRouteTable>,
+    mut deadlines: Query<&mut HttpRequestDeadline>,
     mut events: EventReader<HttpRequestReceivedEvent>,
     mut reply_events: EventWriter<HttpRequestReplyEvent>,
-    mut searcher: ResMut<PathSpecSearcherResource>
+    mut searcher: ResMut<PathSpecSearcherResource>,
+    mut commands: Commands,
 ) {
-    for (entity, spec, _) in modified_path_specs.iter() {
-        searcher.path_set.insert(entity, spec.path.clone());
+    let removed: Vec<Entity> = removed_path_specs.iter().collect();
+    searcher.path_set.retain(|(entity, _)| !removed.contains(entity));
+
+    // Handlers registered in the same frame go in the order they were spawned, so routing doesn't change from run to run.
+    let mut modified: Vec<_> = modified_path_specs.iter().collect();
+    modified.sort_by_key(|(entity, _)| *entity);
+    for (entity, spec) in modified {
+        searcher.register(entity, spec.path.clone());
     }
 
     'outer: 
     for ev in events.iter() {
         let path = PathBuf::from(ev.body.uri().path());
-        for (k, pattern) in searcher.path_set.iter() {
-            if !check_path_matches(&path, pattern) {
-                continue;
-            }
-
-            if let Ok((_, mut mailbox)) = path_mailboxes.get_mut(k.to_owned()) {
-                mailbox.push_message(ev.ent, ev.body.clone());
+        for (k, pattern) in searcher.matches(&path) {
+            if let Ok((_, mut mailbox)) = path_mailboxes.get_mut(k) {
+                if let Ok(mut deadline) = deadlines.get_mut(ev.ent) {
+                    deadline.set_route(format!("{pattern:?} (handler {k:?})"));
+
+                    let timeout = routes.find(&path).and_then(|r| r.settings.request_timeout_secs);
+                    if let Some(secs) = timeout {
+                        deadline.set_timeout(Duration::from_secs(secs));
+                    }
+                }
+
+                commands.entity(ev.ent).insert(HttpMetricsRoute(pattern.to_string_lossy().into_owned()));
+                mailbox.push_message(ev.ent, ev.body.clone(), ev.info.clone());
                 continue 'outer; // Move to the next event, don't fall through!
             }
         }
@@ -124,3 +169,28 @@ pub(in super) fn http_request_sorter_system(
         reply_request_404(&mut reply_events, ev.body.clone(), ev.ent);
     }
 }
+
+/// Reports how many requests each handler has left waiting once they've all had their turn this frame.
+pub(in super) fn http_mailbox_metrics_system(
+    mailboxes: Query<(&HttpHandlerPathSpec, &HttpHandlerRequestMailbox)>,
+    mut metrics: ResMut<HttpMetrics>,
+) {
+    metrics.set_mailbox_depths(mailboxes.iter().map(|(spec, mailbox)| (spec.path.to_string_lossy().into_owned(), mailbox.len())));
+}
+
+/// While shutting down, replies 503 to whatever's still queued in a mailbox once every handler has had its turn this frame.
+pub(in super) fn http_shutdown_mailbox_drain_system(
+    shutdown: Res<HttpShutdownState>,
+    mut mailboxes: Query<&mut HttpHandlerRequestMailbox>,
+    mut reply_events: EventWriter<HttpRequestReplyEvent>,
+) {
+    if !shutdown.is_shutting_down() {
+        return;
+    }
+
+    for mut mailbox in mailboxes.iter_mut() {
+        while let Some((request, body, _)) = mailbox.read_message() {
+            reply_request_503(&mut reply_events, body, request);
+        }
+    }
+}
@@ -1,22 +1,47 @@
 use bevy::prelude::*;
 
-use super::{pathspec::{PathSpecSearcherResource, http_request_sorter_system}, static_page::http_string_serve_system, assets::{WebFileAsset, WebFileLoader, SiteMapAsset, SiteMapLoader}, sitemap::site_map_reloader};
+use crate::{config::{CompressionConfig, ServiceConfig}, http::middleware::HttpFilterAppExt};
+
+use super::{pathspec::{PathSpecSearcherResource, http_request_sorter_system, http_shutdown_mailbox_drain_system, http_mailbox_metrics_system}, static_page::{http_string_serve_system, http_asset_failure_system}, assets::{WebFileAsset, WebFileLoader, SiteMapAsset, SiteMapLoader}, sitemap::{HttpRouteTable, site_map_reloader, http_route_headers_system}, rate_limit::{RateLimiterResource, http_rate_limit_system}, sse::{HttpSsePublishEvent, http_sse_publish_system, http_sse_subscribe_system, http_sse_stream_system}};
 
 /// Provides HTTP page handling, automatically routing requests to any entities with the correct pathspec and mailbox.
 /// To receive routed requests, utilize the HttpHandlerBundle and read new requests from your HttpHandlerRequestMailbox component.
+/// Must be added after the [`crate::http::HttpRequestPlugin`], and after the [`ServiceConfig`] is inserted, as the
+/// compression settings are taken from it when the plugin is built.
 #[derive(Default)]
 pub struct HttpPageHandlerPlugin {}
 
 impl Plugin for HttpPageHandlerPlugin {
     fn build(&self, app: &mut App) {
+        // Assets are compressed as they load, which happens off in the asset loader where resources can't be reached.
+        let compression = match app.world.get_resource::<ServiceConfig>() {
+            Some(cfg) => cfg.compression.clone(),
+            None => {
+                warn!("No ServiceConfig when the page handler plugin was added, static assets are compressed with the default settings.");
+                CompressionConfig::default()
+            }
+        };
+
         app
             .insert_resource(PathSpecSearcherResource::default())
+            .insert_resource(HttpRouteTable::default())
+            .insert_resource(RateLimiterResource::default())
+            .add_request_filter(http_rate_limit_system)
+            .add_response_filter(http_route_headers_system)
             .add_system(http_request_sorter_system)
             .add_system(http_string_serve_system)
+            .add_system(http_asset_failure_system)
+            .add_event::<HttpSsePublishEvent>()
+            .add_system(http_sse_publish_system)
+            .add_system(http_sse_subscribe_system)
+            .add_system(http_sse_stream_system.after(http_sse_publish_system))
             .add_system(site_map_reloader)
+            .add_system_to_stage(CoreStage::PostUpdate, http_mailbox_metrics_system.before(http_shutdown_mailbox_drain_system))
+            .add_system_to_stage(CoreStage::PostUpdate, http_shutdown_mailbox_drain_system)
             .add_asset::<WebFileAsset>()
             .add_asset::<SiteMapAsset>()
-            .add_asset_loader(WebFileLoader())
+            .add_asset_loader(WebFileLoader(compression))
             .add_asset_loader(SiteMapLoader());
     }
-}
\ No newline at end of file
+}
+
@@ -0,0 +1,121 @@
+use std::{collections::HashMap, net::IpAddr, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
+
+use bevy::prelude::*;
+
+use crate::{config::ServiceConfig, http::{events::HttpRequestReplyEvent, middleware::HttpMiddlewareQueue}};
+
+use super::{error_replies::reply_request_429, pathspec::PathSpecSearcherResource, sitemap::HttpRouteTable};
+
+/// How often buckets that have filled back up are thrown away, so clients we haven't seen in a while don't pile up.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a client's bucket covers.
+#[derive(Debug, Hash, PartialEq, Eq)]
+enum BucketScope {
+    /// The whole site.
+    Site,
+    /// Every request to a handler, by its path pattern. Only used with [`crate::config::RateLimitConfig::per_route`].
+    Handler(PathBuf),
+    /// A route with its own limit in a site map, by its pattern.
+    Route(PathBuf),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+    requests_per_second: f64,
+    burst: u32,
+}
+
+impl TokenBucket {
+    fn new(requests_per_second: f64, burst: u32, now: Instant) -> Self {
+        TokenBucket { tokens: burst as f64, last: now, requests_per_second, burst }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let earned = now.duration_since(self.last).as_secs_f64() * self.requests_per_second;
+        self.tokens = (self.tokens + earned).min(self.burst as f64);
+        self.last = now;
+    }
+
+    /// Takes a token out for a request, or returns how long until there'll be one.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::try_from_secs_f64((1.0 - self.tokens) / self.requests_per_second).unwrap_or(Duration::MAX))
+    }
+}
+
+#[derive(Resource)]
+pub(in super) struct RateLimiterResource {
+    buckets: HashMap<(IpAddr, BucketScope), TokenBucket>,
+    last_sweep: Instant,
+}
+
+impl Default for RateLimiterResource {
+    fn default() -> Self {
+        RateLimiterResource { buckets: HashMap::default(), last_sweep: Instant::now() }
+    }
+}
+
+/// A request filter replying 429 (Too Many Requests) to clients that are over their rate limit, so their requests never get routed.
+pub(in super) fn http_rate_limit_system(
+    cfg: Res<ServiceConfig>,
+    mut limiter: ResMut<RateLimiterResource>,
+    searcher: Res<PathSpecSearcherResource>,
+    routes: Res<HttpRouteTable>,
+    mut queue: ResMut<HttpMiddlewareQueue>,
+    mut reply_events: EventWriter<HttpRequestReplyEvent>,
+) {
+    let now = Instant::now();
+    let limiter = &mut *limiter;
+
+    for req in queue.pending() {
+        // Everyone coming in over a unix socket would share one bucket, which isn't much use.
+        let Some(ip) = req.client_addr.ip() else {
+            continue;
+        };
+
+        let path = Path::new(req.request.uri().path());
+        let route_limit = routes.find(path)
+            .and_then(|r| r.settings.rate_limit.as_ref().map(|limit| (&r.pattern, limit)));
+
+        let (scope, requests_per_second, burst) = match route_limit {
+            Some((pattern, limit)) => (BucketScope::Route(pattern.clone()), limit.requests_per_second, limit.burst),
+            None if cfg.rate_limit.enabled => {
+                let scope = match searcher.find_pattern(path) {
+                    Some(pattern) if cfg.rate_limit.per_route => BucketScope::Handler(pattern.to_path_buf()),
+                    _ => BucketScope::Site,
+                };
+                (scope, cfg.rate_limit.requests_per_second, cfg.rate_limit.burst)
+            }
+            None => continue,
+        };
+
+        let bucket = limiter.buckets.entry((ip, scope))
+            .or_insert_with(|| TokenBucket::new(requests_per_second, burst, now));
+        // Pick up any changes to the limits since the bucket was made.
+        bucket.requests_per_second = requests_per_second;
+        bucket.burst = burst;
+
+        if let Err(wait) = bucket.take(now) {
+            let retry_after = wait.as_secs().saturating_add(u64::from(wait.subsec_nanos() > 0));
+            // The request goes no further, so it can be handed over as is.
+            let request = std::mem::take(&mut req.request);
+            reply_request_429(&mut reply_events, Arc::new(request), req.ent, retry_after.max(1));
+            req.stop();
+        }
+    }
+
+    if now.duration_since(limiter.last_sweep) > SWEEP_INTERVAL {
+        limiter.last_sweep = now;
+        limiter.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.burst as f64
+        });
+    }
+}
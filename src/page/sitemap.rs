@@ -1,22 +1,87 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use bevy::prelude::*;
+use bevy::{asset::HandleId, prelude::*};
 use log::info;
 
-use crate::{page::static_page::HttpAssetServeBundle, config::ServiceConfig};
+use http::{header::HeaderName, HeaderValue};
 
-use super::assets::SiteMapAsset;
+use crate::{page::static_page::HttpAssetServeBundle, config::ServiceConfig, http::middleware::HttpReplyQueue};
+
+use super::{assets::{RouteSettings, SiteMapAsset}, pathspec::check_path_matches};
 
 #[derive(Component)]
 pub struct SiteMapController {
     map: Handle<SiteMapAsset>,
 }
 
-pub(in super) fn site_map_reloader(cfg: Res<ServiceConfig>, mut asset_events: EventReader<AssetEvent<SiteMapAsset>>, controllers: Query<(Entity, &mut SiteMapController)>, mut commands: Commands, assets: Res<Assets<SiteMapAsset>>, asset_server: Res<AssetServer>) {
+impl SiteMapController {
+    /// The site map this controller serves.
+    pub fn map(&self) -> &Handle<SiteMapAsset> {
+        &self.map
+    }
+}
+
+/// Route settings from a site map, applied to any request matching the pattern.
+pub struct HttpRouteSettings {
+    pub pattern: PathBuf,
+    pub settings: RouteSettings,
+    /// [`RouteSettings::headers`], parsed once when the site map is loaded. Invalid ones are left out.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// The route settings from every site map, kept in the order the site maps are configured in and the routes are
+/// written in, so the first matching pattern wins.
+#[derive(Resource, Default)]
+pub struct HttpRouteTable {
+    maps: Vec<(HandleId, Vec<HttpRouteSettings>)>,
+}
+
+impl HttpRouteTable {
+    /// Finds the settings for the first route matching the given path, if any.
+    pub fn find(&self, path: &Path) -> Option<&HttpRouteSettings> {
+        self.maps.iter().flat_map(|(_, routes)| routes).find(|r| check_path_matches(path, &r.pattern))
+    }
+
+    /// Holds a place for a site map's routes, so they keep their place in line however the site maps finish loading.
+    fn reserve(&mut self, map: HandleId) {
+        if !self.maps.iter().any(|(id, _)| *id == map) {
+            self.maps.push((map, Vec::new()));
+        }
+    }
+
+    /// Replaces a site map's routes with the ones given.
+    fn set(&mut self, map: HandleId, routes: Vec<HttpRouteSettings>) {
+        self.reserve(map);
+        if let Some((_, slot)) = self.maps.iter_mut().find(|(id, _)| *id == map) {
+            *slot = routes;
+        }
+    }
+}
+
+/// A response filter adding the headers a site map sets for a route to replies on it.
+pub(in super) fn http_route_headers_system(routes: Res<HttpRouteTable>, mut queue: ResMut<HttpReplyQueue>) {
+    for reply in queue.pending() {
+        let Ok(response) = &mut reply.result else {
+            continue;
+        };
+
+        let path = Path::new(reply.uri.path());
+        let Some(route) = routes.find(path) else {
+            continue;
+        };
+
+        for (name, value) in &route.headers {
+            response.headers_mut().entry(name).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+pub(in super) fn site_map_reloader(cfg: Res<ServiceConfig>, mut asset_events: EventReader<AssetEvent<SiteMapAsset>>, controllers: Query<(Entity, &mut SiteMapController)>, mut commands: Commands, assets: Res<Assets<SiteMapAsset>>, asset_server: Res<AssetServer>, mut routes: ResMut<HttpRouteTable>) {
     if controllers.is_empty() {
         for i in &cfg.sitemaps {
             let asset = asset_server.load::<SiteMapAsset, &Path>(i);
-            setup_sitemap(asset, &controllers, &mut commands, &assets, &asset_server);
+            routes.reserve(asset.id());
+            setup_sitemap(asset, &controllers, &mut commands, &assets, &asset_server, &mut routes);
         }
     }  
     
@@ -27,14 +92,14 @@ pub(in super) fn site_map_reloader(cfg: Res<ServiceConfig>, mut asset_events: Ev
             AssetEvent::Modified { handle } | AssetEvent::Created { handle } => {
                 let mut h = handle.clone();
                 h.make_strong(&assets);
-                setup_sitemap(h, &controllers, &mut commands, &assets, &asset_server);
+                setup_sitemap(h, &controllers, &mut commands, &assets, &asset_server, &mut routes);
             }
             _ => (),
         }
     }
 }
 
-pub fn setup_sitemap(handle: Handle<SiteMapAsset>, controllers: &Query<(Entity, &mut SiteMapController)>, commands: &mut Commands, assets: &Res<Assets<SiteMapAsset>>, asset_server: &Res<AssetServer>) {
+pub fn setup_sitemap(handle: Handle<SiteMapAsset>, controllers: &Query<(Entity, &mut SiteMapController)>, commands: &mut Commands, assets: &Res<Assets<SiteMapAsset>>, asset_server: &Res<AssetServer>, routes: &mut HttpRouteTable) {
     let handle = handle.clone();
 
     let curr_controller = 'block: {
@@ -58,14 +123,17 @@ pub fn setup_sitemap(handle: Handle<SiteMapAsset>, controllers: &Query<(Entity,
     };
 
     // this is HAIRY logic, christ.
-    let mut ecommands = if let None = ecommands {
-        let controller = SiteMapController {
-            map: handle.clone(),
-        };
-    
-        info!("Spawned a new sitemap controller.");
-        commands.spawn((controller, Name::new("SiteMap Controller")))
-    } else { ecommands.unwrap() };
+    let mut ecommands = match ecommands {
+        Some(ecmds) => ecmds,
+        None => {
+            let controller = SiteMapController {
+                map: handle.clone(),
+            };
+
+            info!("Spawned a new sitemap controller.");
+            commands.spawn((controller, Name::new("SiteMap Controller")))
+        }
+    };
     
     
     let map = assets.get(&handle);
@@ -80,7 +148,7 @@ pub fn setup_sitemap(handle: Handle<SiteMapAsset>, controllers: &Query<(Entity,
     ecommands.with_children(|b| {
         if let Some(map_real) = map {
             for (path, asset) in &map_real.mapping {
-                let bundle = HttpAssetServeBundle::new(asset, path.clone(), &asset_server);
+                let bundle = HttpAssetServeBundle::new(asset, path.clone(), asset_server);
 
                 if let Err(e) = bundle {
                     error!("Failed to set up {path:?} with {asset:?} due to {}",e);
@@ -91,4 +159,22 @@ pub fn setup_sitemap(handle: Handle<SiteMapAsset>, controllers: &Query<(Entity,
             }
         }
     });
+
+    if let Some(map_real) = map {
+        let mut map_routes = Vec::with_capacity(map_real.routes.len());
+        for (pattern, settings) in &map_real.routes {
+            let headers = settings.headers.iter()
+                .filter_map(|(name, value)| match (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+                    (Ok(name), Ok(value)) => Some((name, value)),
+                    _ => {
+                        warn!("Ignoring the header {name:?}: {value:?} on {pattern:?}, it isn't a valid header.");
+                        None
+                    }
+                })
+                .collect();
+
+            map_routes.push(HttpRouteSettings { pattern: pattern.clone(), settings: settings.clone(), headers });
+        }
+        routes.set(handle.id(), map_routes);
+    }
 }
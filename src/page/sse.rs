@@ -0,0 +1,283 @@
+use std::{collections::{HashMap, VecDeque}, fmt::Write, path::PathBuf, time::{Duration, Instant}};
+
+use bevy::prelude::*;
+use http::{header::{HeaderName, CACHE_CONTROL, CONTENT_TYPE}, Method, Response};
+
+use crate::http::{events::HttpRequestReplyEvent, info::HttpRequestInfo, shutdown::HttpShutdownState, stream::HttpResponseStream};
+
+use super::{error_replies::reply_request_400, pathspec::{HttpHandlerBundle, HttpHandlerRequestMailbox}};
+
+/// The header a reconnecting client sends with the ID of the last event it saw.
+const LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
+/// Don't write more to a subscriber that has this much waiting on it, let it catch up from the history instead.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+/// A single event, as held in a channel's history.
+#[derive(Debug, Clone)]
+struct SseEvent {
+    id: u64,
+    event: Option<String>,
+    data: String,
+}
+
+impl SseEvent {
+    /// Formats the event for the wire. Multi-line data gets a `data:` field per line, with lines ending at any of
+    /// the `\r\n`, `\r` and `\n` clients split on.
+    fn encode(&self, out: &mut String) {
+        let _ = writeln!(out, "id: {}", self.id);
+        if let Some(event) = &self.event {
+            let _ = writeln!(out, "event: {event}");
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            let _ = writeln!(out, "data: {line}");
+        }
+        out.push('\n');
+    }
+}
+
+/// A channel Server-Sent Events are published to, and fanned out from to everyone subscribed to it.
+/// The most recent events are kept, so clients that reconnect with a `Last-Event-ID` can pick up where they left off.
+#[derive(Component)]
+pub struct HttpSseChannel {
+    history: VecDeque<SseEvent>,
+    history_len: usize,
+    /// Every event published since they were last sent out, however many there are, held until every subscriber has
+    /// been sent them. Subscribers are sent these rather than the history, so none are missed for it being short.
+    live: Vec<SseEvent>,
+    keep_alive: Duration,
+    next_id: u64,
+}
+
+impl HttpSseChannel {
+    /// Makes a channel keeping the last `history_len` events for replay, and sending a keep-alive comment to
+    /// subscribers that haven't heard anything in `keep_alive`. Every event is sent to whoever's subscribed when it's
+    /// published whatever the `history_len`, even `0`, which only leaves reconnecting clients nothing to catch up on.
+    pub fn new(history_len: usize, keep_alive: Duration) -> Self {
+        Self {
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            live: Vec::new(),
+            keep_alive,
+            next_id: 1,
+        }
+    }
+
+    /// Publishes an event to everyone subscribed, optionally with an event name for clients to listen for.
+    /// Returns the ID it was given.
+    pub fn publish(&mut self, event: Option<&str>, data: impl Into<String>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // A line break would end the field early, and let the rest pass for fields of its own.
+        let event = event.map(|e| e.replace(['\r', '\n'], ""));
+        let event = SseEvent { id, event, data: data.into() };
+        self.live.push(event.clone());
+        self.history.push_back(event);
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+
+        id
+    }
+
+    /// The ID of the last event published, or 0 if there hasn't been one.
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    /// The events after `seen`, from the history for anything older than the live events.
+    fn events_after(&self, seen: u64) -> impl Iterator<Item = &SseEvent> {
+        let live_from = self.live.first().map_or(u64::MAX, |e| e.id);
+        self.history
+            .iter()
+            .filter(move |e| e.id > seen && e.id < live_from)
+            .chain(self.live.iter().filter(move |e| e.id > seen))
+    }
+}
+
+/// Publishes an event to the channel on the given entity, for systems that would rather not query channels themselves.
+#[derive(Debug)]
+pub struct HttpSsePublishEvent {
+    pub channel: Entity,
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Attached to a request entity subscribed to a channel. The response stays open until the channel goes away, the
+/// client disconnects, or the server shuts down.
+#[derive(Component)]
+pub struct HttpSseSubscriber {
+    /// The entity with the [`HttpSseChannel`].
+    pub channel: Entity,
+    /// The ID of the last event sent.
+    last_id: u64,
+    last_write: Instant,
+}
+
+/// A Server-Sent Events endpoint. GET requests to the path subscribe to the channel on the same entity.
+#[derive(Bundle)]
+pub struct HttpSseBundle {
+    #[bundle]
+    handler: HttpHandlerBundle,
+    channel: HttpSseChannel,
+    name: Name,
+}
+
+impl HttpSseBundle {
+    /// See [`HttpSseChannel::new`].
+    pub fn new(serve_path: PathBuf, history_len: usize, keep_alive: Duration) -> Self {
+        Self {
+            name: Name::new(format!("SSE Channel `{serve_path:?}`")),
+            handler: HttpHandlerBundle::new(serve_path),
+            channel: HttpSseChannel::new(history_len, keep_alive),
+        }
+    }
+}
+
+pub(in super) fn http_sse_publish_system(mut events: EventReader<HttpSsePublishEvent>, mut channels: Query<&mut HttpSseChannel>) {
+    for ev in events.iter() {
+        match channels.get_mut(ev.channel) {
+            Ok(mut channel) => {
+                channel.publish(ev.event.as_deref(), ev.data.clone());
+            }
+            Err(_) => warn!("Tried to publish an event to {:?}, which isn't an SSE channel.", ev.channel),
+        }
+    }
+}
+
+/// Replies to requests for an SSE channel with an open event stream, subscribing them to it.
+pub(in super) fn http_sse_subscribe_system(
+    mut channels: Query<(Entity, &HttpSseChannel, &mut HttpHandlerRequestMailbox)>,
+    mut reply_events: EventWriter<HttpRequestReplyEvent>,
+    mut commands: Commands,
+) {
+    for (channel_ent, channel, mut mailbox) in channels.iter_mut() {
+        while let Some((request, body, info)) = mailbox.read_message() {
+            if body.method() != Method::GET {
+                reply_request_400(&mut reply_events, body, request);
+                continue;
+            }
+
+            // An ID from before a restart can be ahead of ours, in which case everything we have is news to the client.
+            let last_id = match body.headers().get(LAST_EVENT_ID).and_then(|v| v.to_str().ok()?.trim().parse().ok()) {
+                Some(id) if id <= channel.last_id() => id,
+                Some(_) => 0,
+                None => channel.last_id(),
+            };
+
+            let head = Response::builder()
+                .header(CONTENT_TYPE, "text/event-stream")
+                .header(CACHE_CONTROL, "no-cache")
+                .body(())
+                .unwrap();
+            reply_events.send(HttpRequestReplyEvent::streaming(head, request));
+
+            info!("[{}] Subscribed to SSE channel {channel_ent:?} from event {last_id}.", info.id);
+            commands.entity(request).insert(HttpSseSubscriber {
+                channel: channel_ent,
+                last_id,
+                last_write: Instant::now(),
+            });
+        }
+    }
+}
+
+/// Catches every subscriber up on its channel's events, and keeps quiet connections alive. Subscribers that are too far
+/// behind to be written to are caught up from the history once they've read what they have, and miss whatever's
+/// fallen out of it by then.
+pub(in super) fn http_sse_stream_system(
+    mut subscribers: Query<(&mut HttpSseSubscriber, Option<&mut HttpResponseStream>, &HttpRequestInfo)>,
+    mut channels: Query<(Entity, &mut HttpSseChannel)>,
+    shutdown: Res<HttpShutdownState>,
+) {
+    let now = Instant::now();
+    // The oldest event each channel's newest subscribers are waiting on, as they only get a stream a frame later.
+    let mut waiting = HashMap::<Entity, u64>::new();
+
+    for (mut subscriber, stream, info) in subscribers.iter_mut() {
+        let Some(mut stream) = stream else {
+            let seen = waiting.entry(subscriber.channel).or_insert(u64::MAX);
+            *seen = (*seen).min(subscriber.last_id);
+            continue;
+        };
+
+        let Ok((_, channel)) = channels.get(subscriber.channel) else {
+            stream.finish();
+            continue;
+        };
+
+        // The stream would otherwise hold the connection open for the whole grace period.
+        if shutdown.is_shutting_down() {
+            stream.finish();
+            continue;
+        }
+
+        if stream.buffered() > MAX_BUFFERED {
+            continue;
+        }
+
+        let mut out = String::new();
+        let seen = subscriber.last_id;
+        for event in channel.events_after(seen) {
+            // Catching up from 0 is for clients that don't know where they're up to, so they aren't missing anything.
+            if seen > 0 && subscriber.last_id + 1 < event.id {
+                warn!(
+                    "[{}] Events {} to {} on SSE channel {:?} were gone from the history before they were sent.",
+                    info.id,
+                    subscriber.last_id + 1,
+                    event.id - 1,
+                    subscriber.channel
+                );
+            }
+            event.encode(&mut out);
+            subscriber.last_id = event.id;
+        }
+
+        if out.is_empty() && now.duration_since(subscriber.last_write) >= channel.keep_alive {
+            out.push_str(": keep-alive\n\n");
+        }
+
+        if !out.is_empty() {
+            stream.send(out);
+            subscriber.last_write = now;
+        }
+    }
+
+    for (ent, mut channel) in channels.iter_mut() {
+        if channel.live.is_empty() {
+            continue;
+        }
+
+        match waiting.get(&ent) {
+            Some(&seen) => channel.live.retain(|e| e.id > seen),
+            None => channel.live.clear(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &str) -> String {
+        let mut out = String::new();
+        SseEvent { id: 7, event: None, data: data.to_string() }.encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn splits_data_on_every_line_ending() {
+        assert_eq!(encode("one"), "id: 7\ndata: one\n\n");
+        assert_eq!(encode("one\ntwo\r\nthree\rfour"), "id: 7\ndata: one\ndata: two\ndata: three\ndata: four\n\n");
+        assert_eq!(encode("one\n\ntwo\r"), "id: 7\ndata: one\ndata: \ndata: two\ndata: \n\n");
+    }
+
+    #[test]
+    fn data_cannot_inject_fields() {
+        // Every line a client would see has to be a data field.
+        let out = encode("hi\revent: admin\rid: 1");
+        for line in out.split(['\r', '\n']).filter(|l| !l.is_empty()).skip(1) {
+            assert!(line.starts_with("data: "), "{line:?} isn't data");
+        }
+    }
+}
@@ -1,11 +1,11 @@
-use std::{path::{Path, PathBuf}};
-use bevy::prelude::*;
-use http::{Response, Method};
+use std::{collections::HashSet, path::{Path, PathBuf}};
+use bevy::{asset::{HandleId, LoadState}, prelude::*};
+use http::{header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY}, Response, Method};
 use hyper::Body;
 
-use crate::{http::events::HttpRequestReplyEvent, page::error_replies::reply_request_500};
+use crate::{http::{events::HttpRequestReplyEvent, info::HttpRequestId, metrics::HttpMetrics}, page::error_replies::reply_request_500};
 
-use super::{pathspec::{HttpHandlerBundle, HttpHandlerRequestMailbox, HttpHandlerPathSpec}, error_replies::{reply_request_400, reply_request_503}, assets::WebFileAsset};
+use super::{pathspec::{HttpHandlerBundle, HttpHandlerRequestMailbox, HttpHandlerPathSpec}, error_replies::{reply_request_400, reply_request_503}, assets::{mime_type_of, WebFileAsset}, compression::negotiate};
 
 /// A very simple server that replies to GET requests with pre-loaded data.
 #[derive(Component)]
@@ -21,6 +21,11 @@ impl HttpAssetServeComponent {
             mimetype
         }
     }
+
+    /// The asset being served.
+    pub fn data(&self) -> &Handle<WebFileAsset> {
+        &self.data
+    }
 }
 
 #[derive(Bundle)]
@@ -34,11 +39,7 @@ pub struct HttpAssetServeBundle {
 impl HttpAssetServeBundle {
     pub fn new(file_path: &Path, serve_path: PathBuf, asset_server: &AssetServer) -> Result<Self, std::io::Error> {
         trace!("Building a new asset server, mapping {file_path:?} to URI {serve_path:?}");
-        let mimetype = file_path
-            .extension()
-            .and_then(|v| v.to_str())
-            .and_then(|v| mime_guess::from_ext(v).first_raw())
-            .unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM.essence_str());
+        let mimetype = mime_type_of(file_path);
 
         Ok(Self {
             name: Name::new(format!("Asset Server `{serve_path:?}`")),
@@ -56,19 +57,28 @@ pub(in super) fn http_string_serve_system(
 ) {
     for (serve, mut mailbox, pathspec) in waiting_requests.iter_mut() {
 
-        while let Some((request, body)) = mailbox.read_message() {
+        while let Some((request, body, _)) = mailbox.read_message() {
             if body.method() != Method::GET {
                 reply_request_400(&mut reply_events, body, request);
                 continue;
             }
 
             if let Some(v) = assets.get(&serve.data) {
-                let response = Response::builder()
-                    .header("Content-Type", serve.mimetype)
-                    .body(Body::from(v.data.clone()));
+                let mut response = Response::builder().header(CONTENT_TYPE, serve.mimetype);
+                // Caches have to know the reply depends on Accept-Encoding, even when it goes out uncompressed.
+                if !v.encoded.is_empty() {
+                    response = response.header(VARY, "Accept-Encoding");
+                }
+
+                let response = match negotiate(body.headers().get(ACCEPT_ENCODING), &v.encoded) {
+                    Some(variant) => response
+                        .header(CONTENT_ENCODING, variant.encoding.token())
+                        .body(Body::from(variant.data.clone())),
+                    None => response.body(Body::from(v.data.clone())),
+                };
                 
                 if let Err(e) = response {
-                    error!("Got error while trying to serve {:?}, error is: {}", pathspec.path(), e);
+                    error!("[{}] Got error while trying to serve {:?}, error is: {}", HttpRequestId::of(&body).unwrap_or("-"), pathspec.path(), e);
                     reply_request_500(&mut reply_events, body, request);
                 } else {
                     reply_events.send(HttpRequestReplyEvent::new(Ok(response.unwrap()), request));
@@ -78,4 +88,24 @@ pub(in super) fn http_string_serve_system(
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Counts served assets that fail to load in the metrics, once per failure.
+pub(in super) fn http_asset_failure_system(
+    servers: Query<&HttpAssetServeComponent>,
+    asset_server: Res<AssetServer>,
+    mut failed: Local<HashSet<HandleId>>,
+    mut metrics: ResMut<HttpMetrics>,
+) {
+    for serve in servers.iter() {
+        let id = serve.data.id();
+        if asset_server.get_load_state(id) == LoadState::Failed {
+            if failed.insert(id) {
+                metrics.record_asset_load_failure();
+            }
+        } else {
+            // It may yet fail again, after being fixed and reloaded.
+            failed.remove(&id);
+        }
+    }
+}
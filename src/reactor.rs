@@ -0,0 +1,81 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use bevy::prelude::Resource;
+use log::error;
+use tokio::runtime::{Builder, Handle};
+
+/// A tokio IO driver running on its own thread.
+/// Sockets registered with it get readiness notifications from epoll (or the platform equivalent),
+/// which wake whatever task is polling them, so the futures themselves can stay on bevy's task pools.
+#[derive(Resource, Clone)]
+pub(crate) struct IoReactor {
+    handle: Handle,
+}
+
+impl IoReactor {
+    /// Spins up the reactor thread. The thread lives for the rest of the process.
+    pub fn start() -> io::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_io().build()?;
+        let handle = runtime.handle().clone();
+
+        thread::Builder::new()
+            .name("IO Reactor".to_string())
+            // A current-thread runtime only drives its IO while something is blocked on it.
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+
+        Ok(Self { handle })
+    }
+
+    /// Registers a non-blocking std TCP stream with the reactor.
+    pub fn register_tcp(&self, stream: std::net::TcpStream) -> io::Result<tokio::net::TcpStream> {
+        let _guard = self.handle.enter();
+        tokio::net::TcpStream::from_std(stream)
+    }
+
+    /// Registers a non-blocking std unix domain socket stream with the reactor.
+    #[cfg(unix)]
+    pub fn register_unix(&self, stream: std::os::unix::net::UnixStream) -> io::Result<tokio::net::UnixStream> {
+        let _guard = self.handle.enter();
+        tokio::net::UnixStream::from_std(stream)
+    }
+
+    /// Listens for the process being asked to stop, by SIGINT (Ctrl+C) or, on unix, SIGTERM.
+    /// The returned flag is set once either arrives. This replaces the default handling, so the process no longer
+    /// dies on them by itself.
+    pub fn shutdown_signal(&self) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+
+        let interrupted = flag.clone();
+        self.handle.spawn(async move {
+            match tokio::signal::ctrl_c().await {
+                Ok(()) => interrupted.store(true, Ordering::Relaxed),
+                Err(e) => error!("Couldn't listen for SIGINT: {e}"),
+            }
+        });
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let terminated = flag.clone();
+            self.handle.spawn(async move {
+                match signal(SignalKind::terminate()) {
+                    Ok(mut s) => {
+                        s.recv().await;
+                        terminated.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => error!("Couldn't listen for SIGTERM: {e}"),
+                }
+            });
+        }
+
+        flag
+    }
+}
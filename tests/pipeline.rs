@@ -0,0 +1,495 @@
+use std::{
+    error::Error,
+    future::Future,
+    io::{self, Read},
+    net::SocketAddr,
+    path::PathBuf,
+    pin::pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use bevyblog::{
+    config::ServiceConfig,
+    http::{
+        error::{HttpErrorRenderer, HttpHandlerError},
+        events::HttpRequestReplyEvent,
+        info::X_REQUEST_ID,
+        stream::HttpResponseStream,
+        testing::HttpTestClient,
+    },
+    page::{
+        pathspec::{HttpHandlerBundle, HttpHandlerRequestMailbox},
+        sse::{HttpSseBundle, HttpSseChannel, HttpSseSubscriber},
+    },
+};
+use flate2::read::GzDecoder;
+use http::{
+    header::{ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER, SERVER, UPGRADE, VARY},
+    Request, Response, StatusCode,
+};
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body,
+};
+
+/// The default site map, plus whatever else the test needs.
+fn config(extra: &str) -> ServiceConfig {
+    ron::from_str(&format!(r#"(sitemaps: ["default.map"], bind_addresses: [], {extra})"#)).unwrap()
+}
+
+fn client(extra: &str) -> HttpTestClient {
+    let mut client = HttpTestClient::new(config(extra));
+    client.wait_for_assets();
+    client
+}
+
+#[derive(Component)]
+struct Hello;
+
+#[derive(Component)]
+struct Streamed;
+
+#[derive(Component)]
+struct Failing;
+
+/// Replies to `/hello` with a greeting, to `/stream` with a body written over two frames, and to `/fail/*` with an
+/// error.
+fn add_handlers(client: &mut HttpTestClient) {
+    fn hello(mut handlers: Query<&mut HttpHandlerRequestMailbox, With<Hello>>, mut replies: EventWriter<HttpRequestReplyEvent>) {
+        for mut mailbox in handlers.iter_mut() {
+            while let Some((ent, _, info)) = mailbox.read_message() {
+                let response = Response::new(Body::from(format!("hello {}", info.peer_addr)));
+                replies.send(HttpRequestReplyEvent::new(Ok(response), ent));
+            }
+        }
+    }
+
+    fn stream(
+        mut handlers: Query<&mut HttpHandlerRequestMailbox, With<Streamed>>,
+        mut replies: EventWriter<HttpRequestReplyEvent>,
+        mut streams: Query<&mut HttpResponseStream>,
+    ) {
+        for mut stream in streams.iter_mut() {
+            stream.send("world");
+            stream.finish();
+        }
+
+        for mut mailbox in handlers.iter_mut() {
+            while let Some((ent, _, _)) = mailbox.read_message() {
+                replies.send(HttpRequestReplyEvent::streaming(Response::new(()), ent));
+            }
+        }
+    }
+
+    fn fail(mut handlers: Query<&mut HttpHandlerRequestMailbox, With<Failing>>, mut replies: EventWriter<HttpRequestReplyEvent>) {
+        for mut mailbox in handlers.iter_mut() {
+            while let Some((ent, body, _)) = mailbox.read_message() {
+                let error: Box<dyn Error + Send + Sync> = match body.uri().path() {
+                    "/fail/forbidden" => Box::new(HttpHandlerError::new(StatusCode::FORBIDDEN).with_message("Members only.")),
+                    _ => Box::new(io::Error::other("the database caught fire")),
+                };
+                replies.send(HttpRequestReplyEvent::new(Err(error), ent));
+            }
+        }
+    }
+
+    let app = client.app();
+    app.world.spawn((HttpHandlerBundle::new(PathBuf::from("/hello")), Hello));
+    app.world.spawn((HttpHandlerBundle::new(PathBuf::from("/stream")), Streamed));
+    app.world.spawn((HttpHandlerBundle::new(PathBuf::from("/fail/*")), Failing));
+    app.add_system(hello).add_system(stream).add_system(fail);
+}
+
+/// Adds an SSE channel at `/events`.
+fn add_sse_channel(client: &mut HttpTestClient, history_len: usize, keep_alive: Duration) {
+    let bundle = HttpSseBundle::new(PathBuf::from("/events"), history_len, keep_alive);
+    client.app().world.spawn(bundle);
+}
+
+fn publish(client: &mut HttpTestClient, data: &str) -> u64 {
+    let world = &mut client.app().world;
+    world.query::<&mut HttpSseChannel>().single_mut(world).publish(None, data)
+}
+
+/// Subscribes to the channel at `/events`, returning the event stream.
+fn subscribe(client: &mut HttpTestClient, last_event_id: Option<&str>) -> Body {
+    let mut request = Request::get("/events");
+    if let Some(id) = last_event_id {
+        request = request.header("Last-Event-ID", id);
+    }
+
+    let response = client.send(request.body(Bytes::new()).unwrap()).unwrap();
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+    response.into_body()
+}
+
+/// Steps the schedule until the next chunk of a streamed body comes through.
+fn next_chunk(client: &mut HttpTestClient, body: &mut Body) -> String {
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut chunk = None;
+    client.update_until("a chunk", |_| match pin!(body.data()).poll(&mut cx) {
+        Poll::Ready(Some(data)) => {
+            chunk = Some(data.unwrap());
+            true
+        }
+        Poll::Ready(None) => panic!("The stream ended."),
+        Poll::Pending => false,
+    });
+    String::from_utf8(chunk.unwrap().to_vec()).unwrap()
+}
+
+#[test]
+fn serves_pages_from_the_site_map() {
+    let mut client = client("");
+    let response = client.get("/");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/html");
+    assert!(response.body().starts_with(b"<!DOCTYPE html>"));
+    assert!(response.headers().contains_key(X_REQUEST_ID));
+}
+
+#[test]
+fn unrouted_requests_are_404() {
+    let mut client = client("");
+    assert_eq!(client.get("/nowhere").status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn static_pages_only_take_get() {
+    let mut client = client("");
+    let request = Request::post("/").body(Bytes::from("hi")).unwrap();
+    let response = client.send(request).unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn routes_to_handlers() {
+    let mut client = client("").with_peer_addr(SocketAddr::from(([10, 1, 2, 3], 1234)));
+    add_handlers(&mut client);
+    let response = client.get("/hello");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body(), "hello 10.1.2.3:1234");
+}
+
+#[test]
+fn routes_to_the_first_handler_registered() {
+    let mut client = client("");
+    add_handlers(&mut client);
+    // Would catch everything, if it were first.
+    client.app().world.spawn(HttpHandlerBundle::new(PathBuf::from("/*")));
+
+    assert_eq!(client.get("/hello").status(), StatusCode::OK);
+    assert_eq!(client.get("/main.less").status(), StatusCode::OK);
+}
+
+#[test]
+fn reads_streamed_bodies() {
+    let mut client = client("");
+    add_handlers(&mut client);
+
+    assert_eq!(client.get("/stream").body(), "world");
+}
+
+#[test]
+fn compresses_when_asked() {
+    let mut client = client("");
+    let plain = client.get("/main.less");
+
+    let request = Request::get("/main.less").header(ACCEPT_ENCODING, "gzip").body(Bytes::new()).unwrap();
+    let response = client.send(request).unwrap();
+    let response = client.read_body(response);
+
+    assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+    assert_eq!(response.headers()[VARY], "Accept-Encoding");
+    assert_eq!(plain.headers()[VARY], "Accept-Encoding");
+
+    let mut decoded = Vec::new();
+    GzDecoder::new(&response.body()[..]).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, &plain.body()[..]);
+}
+
+#[test]
+fn applies_configured_headers() {
+    let mut client = client(r#"headers: (add: [("Server", "bevyblog")])"#);
+    let response = client.get("/nowhere");
+
+    assert_eq!(response.headers()[SERVER], "bevyblog");
+}
+
+#[test]
+fn applies_configured_headers_to_replies_made_before_routing() {
+    let headers = r#"headers: (add: [("Server", "bevyblog")])"#;
+
+    let mut small_bodies = client(&format!("{headers}, limits: (max_request_body_size: 4)"));
+    let request = Request::post("/").body(Bytes::from("too large")).unwrap();
+    let response = small_bodies.send(request).unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.headers()[SERVER], "bevyblog");
+
+    let mut no_queue = client(&format!("{headers}, limits: (max_queued_requests: 0)"));
+    let response = no_queue.get("/");
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[SERVER], "bevyblog");
+    assert!(response.headers().contains_key(RETRY_AFTER));
+}
+
+#[test]
+fn logs_requests_turned_away_before_routing() {
+    let path = std::env::temp_dir().join(format!("bevyblog-pipeline-{}-access.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let log = format!("access_log: Some((path: {path:?}))");
+
+    let mut small_bodies = client(&format!("{log}, limits: (max_request_body_size: 4)"));
+    let request = Request::post("/upload").body(Bytes::from("too large")).unwrap();
+    small_bodies.send(request).unwrap();
+    small_bodies.update();
+
+    let mut no_queue = client(&format!("{log}, limits: (max_queued_requests: 0)"));
+    no_queue.get("/queued");
+    no_queue.update();
+
+    let lines = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(lines.contains(r#""POST /upload HTTP/1.1" 413 "#), "{lines}");
+    assert!(lines.contains(r#""GET /queued HTTP/1.1" 503 "#), "{lines}");
+}
+
+#[test]
+fn applies_the_first_matching_route() {
+    let mut client = HttpTestClient::new(ron::from_str(r#"(sitemaps: ["tests/routes.map"], bind_addresses: [])"#).unwrap());
+    client.wait_for_assets();
+
+    assert_eq!(client.get("/index.html").headers()["x-route"], "index");
+    assert_eq!(client.get("/main.less").headers()["x-route"], "everything");
+}
+
+#[test]
+fn keeps_request_ids_from_trusted_proxies_only() {
+    let request = || Request::get("/").header(X_REQUEST_ID, "upstream-id").body(Bytes::new()).unwrap();
+
+    let mut trusted = client(r#"proxy: (trusted_proxies: ["127.0.0.1"])"#);
+    let response = trusted.send(request()).unwrap();
+    assert_eq!(response.headers()[X_REQUEST_ID], "upstream-id");
+
+    let mut untrusted = client("");
+    let response = untrusted.send(request()).unwrap();
+    assert_ne!(response.headers()[X_REQUEST_ID], "upstream-id");
+}
+
+#[test]
+fn times_out_requests_nobody_replies_to() {
+    let mut client = client("limits: (request_timeout_secs: 1)");
+    client.app().world.spawn(HttpHandlerBundle::new(PathBuf::from("/void")));
+
+    let response = client.get("/void");
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(response.body().starts_with(b"504 Gateway Timeout.\nRequest ID: "));
+}
+
+#[test]
+fn counts_requests_in_the_metrics() {
+    let mut client = client("metrics: Some(())");
+    client.get("/");
+    client.get("/nowhere");
+
+    let metrics = client.get("/metrics");
+    let text = String::from_utf8_lossy(metrics.body());
+    assert!(text.contains(r#"bevyblog_requests_total{route="/",method="GET",status="200"} 1"#));
+    assert!(text.contains(r#"bevyblog_requests_total{route="unrouted",method="GET",status="404"} 1"#));
+}
+
+#[test]
+fn sorts_latency_buckets() {
+    let mut client = client("metrics: Some((latency_buckets: [1.0, 0.5, 1.0]))");
+    client.get("/");
+
+    let metrics = client.get("/metrics");
+    let text = String::from_utf8_lossy(metrics.body());
+    let buckets: Vec<_> = text
+        .lines()
+        .filter(|l| l.starts_with(r#"bevyblog_request_duration_seconds_bucket{route="/""#))
+        .map(|l| l.split("le=").nth(1).unwrap().split('}').next().unwrap())
+        .collect();
+    assert_eq!(buckets, [r#""0.5""#, r#""1""#, r#""+Inf""#]);
+}
+
+#[test]
+fn sums_mailbox_depths_by_route() {
+    let mut client = client("metrics: Some(())");
+    client.app().world.spawn(HttpHandlerBundle::new(PathBuf::from("/void")));
+    client.app().world.spawn(HttpHandlerBundle::new(PathBuf::from("/void")));
+    client.update();
+
+    let metrics = client.get("/metrics");
+    let text = String::from_utf8_lossy(metrics.body());
+    let series: Vec<_> = text.lines().filter(|l| l.starts_with(r#"bevyblog_mailbox_depth{route="/void"}"#)).collect();
+    assert_eq!(series, [r#"bevyblog_mailbox_depth{route="/void"} 0"#]);
+}
+
+#[test]
+fn metrics_listeners_serve_nothing_else() {
+    let mut client = client(r#"metrics: Some((listeners: ["127.0.0.1:9090"]))"#).with_listener("127.0.0.1:9090");
+
+    assert_eq!(client.get("/").status(), StatusCode::NOT_FOUND);
+    assert_eq!(client.get("/metrics").status(), StatusCode::OK);
+}
+
+#[test]
+fn renders_handler_errors() {
+    let mut client = client(r#"headers: (add: [("Server", "bevyblog")])"#);
+    add_handlers(&mut client);
+
+    let response = client.get("/fail/forbidden");
+    let id = response.headers()[X_REQUEST_ID].to_str().unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.body(), &format!("403 Forbidden.\nMembers only.\nRequest ID: {id}")[..]);
+    // Error pages still go through the response filters.
+    assert_eq!(response.headers()[SERVER], "bevyblog");
+
+    let response = client.get("/fail/other");
+    let body = String::from_utf8_lossy(response.body());
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.starts_with("500 Internal Server Error.\nRequest ID: "));
+    assert!(!body.contains("database"));
+}
+
+#[test]
+fn renders_errors_with_a_custom_renderer() {
+    let mut client = client("");
+    add_handlers(&mut client);
+    client.app().insert_resource(HttpErrorRenderer::new(|ctx| {
+        Response::new(Body::from(format!("{{\"status\":{},\"request_id\":\"{}\"}}", ctx.status.as_u16(), ctx.request_id)))
+    }));
+
+    let response = client.get("/fail/forbidden");
+    let id = response.headers()[X_REQUEST_ID].to_str().unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.body(), &format!("{{\"status\":403,\"request_id\":\"{id}\"}}")[..]);
+
+    // The server's own error pages use it too.
+    let response = client.get("/nowhere");
+    let id = response.headers()[X_REQUEST_ID].to_str().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.body(), &format!("{{\"status\":404,\"request_id\":\"{id}\"}}")[..]);
+}
+
+#[test]
+fn renders_rate_limits_with_their_headers() {
+    let mut client = client("rate_limit: (enabled: true, requests_per_second: 0.001, burst: 1)");
+    assert_eq!(client.get("/").status(), StatusCode::OK);
+
+    let response = client.get("/");
+    let id = response.headers()[X_REQUEST_ID].to_str().unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.body(), &format!("429 Too Many Requests.\nRequest ID: {id}")[..]);
+    assert!(response.headers().contains_key(RETRY_AFTER));
+}
+
+#[test]
+fn fans_sse_events_out_to_every_subscriber() {
+    let mut client = client("");
+    // Nothing's kept for replay, and more is published at once than would be.
+    add_sse_channel(&mut client, 0, Duration::from_secs(60));
+    let mut first = subscribe(&mut client, None);
+    let mut second = subscribe(&mut client, None);
+
+    publish(&mut client, "one");
+    publish(&mut client, "two");
+    let expected = "id: 1\ndata: one\n\nid: 2\ndata: two\n\n";
+    assert_eq!(next_chunk(&mut client, &mut first), expected);
+    assert_eq!(next_chunk(&mut client, &mut second), expected);
+}
+
+#[test]
+fn catches_sse_subscribers_up_from_their_last_event_id() {
+    let mut client = client("");
+    add_sse_channel(&mut client, 16, Duration::from_secs(60));
+    for data in ["one", "two", "three"] {
+        publish(&mut client, data);
+    }
+
+    let mut resumed = subscribe(&mut client, Some("1"));
+    assert_eq!(next_chunk(&mut client, &mut resumed), "id: 2\ndata: two\n\nid: 3\ndata: three\n\n");
+
+    // From before a restart, so everything we have is news.
+    let mut ahead = subscribe(&mut client, Some("99"));
+    assert_eq!(
+        next_chunk(&mut client, &mut ahead),
+        "id: 1\ndata: one\n\nid: 2\ndata: two\n\nid: 3\ndata: three\n\n"
+    );
+
+    let mut fresh = subscribe(&mut client, None);
+    publish(&mut client, "four");
+    assert_eq!(next_chunk(&mut client, &mut fresh), "id: 4\ndata: four\n\n");
+}
+
+#[test]
+fn keeps_quiet_sse_streams_alive() {
+    let mut client = client("");
+    add_sse_channel(&mut client, 16, Duration::from_millis(10));
+    let mut events = subscribe(&mut client, None);
+
+    assert_eq!(next_chunk(&mut client, &mut events), ": keep-alive\n\n");
+}
+
+#[test]
+fn holds_off_sse_subscribers_with_too_much_buffered() {
+    let mut client = client("");
+    add_sse_channel(&mut client, 16, Duration::from_secs(60));
+    let mut events = subscribe(&mut client, None);
+    let large = "x".repeat(70 * 1024);
+
+    // The first fills the body channel, and the second is left buffered behind it as nothing's reading.
+    publish(&mut client, &large);
+    client.update();
+    publish(&mut client, &large);
+    client.update();
+
+    let buffered = |client: &mut HttpTestClient| {
+        let world = &mut client.app().world;
+        world.query_filtered::<&HttpResponseStream, With<HttpSseSubscriber>>().single(world).buffered()
+    };
+    let before = buffered(&mut client);
+    assert!(before > 64 * 1024);
+
+    publish(&mut client, "small");
+    client.update();
+    assert_eq!(buffered(&mut client), before);
+
+    assert!(next_chunk(&mut client, &mut events).starts_with("id: 1\n"));
+    assert!(next_chunk(&mut client, &mut events).starts_with("id: 2\n"));
+    // Caught up from the history once there's room.
+    assert_eq!(next_chunk(&mut client, &mut events), "id: 3\ndata: small\n\n");
+}
+
+#[test]
+fn keeps_the_upgrade_header_on_the_last_request() {
+    fn accept(mut handlers: Query<&mut HttpHandlerRequestMailbox>, mut replies: EventWriter<HttpRequestReplyEvent>) {
+        for mut mailbox in handlers.iter_mut() {
+            while let Some((ent, body, _)) = mailbox.read_message() {
+                replies.send(HttpRequestReplyEvent::websocket(&body, ent).unwrap());
+            }
+        }
+    }
+
+    let mut client = client("keep_alive: (max_requests: 1)");
+    client.app().world.spawn(HttpHandlerBundle::new(PathBuf::from("/socket")));
+    client.app().add_system(accept);
+
+    let request = Request::get("/socket")
+        .header(CONNECTION, "Upgrade")
+        .header(UPGRADE, "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .body(Bytes::new())
+        .unwrap();
+    let response = client.send(request).unwrap();
+
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(response.headers()[CONNECTION], "upgrade");
+}